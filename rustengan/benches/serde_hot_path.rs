@@ -0,0 +1,253 @@
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BatchSize, BenchmarkGroup, Criterion, Throughput,
+};
+use rustengan::nodes::broadcast::{BroadcastNode, BroadcastPayload, InjectedPayload};
+use rustengan::nodes::echo::{EchoNode, EchoPayload};
+use rustengan::nodes::kafka::KafkaPayload;
+use rustengan::{test_harness, Event, Message, MessageBody, MsgId, NodeId};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Deserializer;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+Baseline for how much of a node's time goes to serde, before touching the writer. For each payload shape (echo,
+broadcast, kafka) a batch of representative requests is deserialized from one newline-delimited buffer, as the stdin
+reader sees them, and a batch of replies is serialized with Message::send, as step does. Echo and broadcast are also
+stepped end to end through test_harness::drive; kafka's handlers wait on lin-kv, so it only gets the serde halves.
+Nothing touches real stdio. Run with `cargo bench --bench serde_hot_path`.
+Replies are also serialized the old way, a fresh serde_json::to_vec per message, and each group prints how many
+allocations a reply costs both ways, to show what Message::send's scratch buffer saves.
+*/
+
+const BATCH: usize = 1000;
+
+/* Counts every allocation, so a bench can report how many a piece of work made */
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// Allocations per message made by `work` over a batch of `messages`
+fn allocations_per_message(messages: usize, mut work: impl FnMut()) -> f64 {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    work();
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / messages as f64
+}
+
+fn message<P>(
+    src: &str,
+    dest: &str,
+    msg_id: usize,
+    in_reply_to: Option<usize>,
+    payload: P,
+) -> Message<P> {
+    Message {
+        src: NodeId::from(src),
+        dest: NodeId::from(dest),
+        body: MessageBody {
+            msg_id: Some(MsgId(msg_id)),
+            in_reply_to: in_reply_to.map(MsgId),
+            payload,
+        },
+    }
+}
+
+// One message per line, as Maelstrom writes them
+fn lines<P: Serialize>(messages: &[Message<P>]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for message in messages {
+        message
+            .send(&mut bytes)
+            .expect("serializing a bench message");
+    }
+    bytes
+}
+
+fn bench_serde<P: Serialize + DeserializeOwned>(
+    group: &mut BenchmarkGroup<WallTime>,
+    group_name: &str,
+    requests: &[Message<P>],
+    replies: &[Message<P>],
+) {
+    let input = lines(requests);
+    group.bench_function("deserialize_requests", |b| {
+        b.iter(|| {
+            Deserializer::from_slice(black_box(&input))
+                .into_iter::<Message<P>>()
+                .collect::<Result<Vec<_>, _>>()
+                .expect("deserializing bench requests")
+        })
+    });
+    let mut output = Vec::with_capacity(lines(replies).len());
+    let send = |output: &mut Vec<u8>| {
+        output.clear();
+        for reply in black_box(replies) {
+            reply.send(output).expect("serializing a bench reply");
+        }
+    };
+    // What send did before it had a scratch buffer
+    let to_vec = |output: &mut Vec<u8>| {
+        output.clear();
+        for reply in black_box(replies) {
+            let mut line = serde_json::to_vec(reply).expect("serializing a bench reply");
+            line.push(b'\n');
+            output.extend_from_slice(&line);
+        }
+    };
+    // Warm up first, so the scratch buffer has already grown to fit
+    send(&mut output);
+    println!(
+        "{}: {:.2} allocations per reply with Message::send, {:.2} with to_vec",
+        group_name,
+        allocations_per_message(replies.len(), || send(&mut output)),
+        allocations_per_message(replies.len(), || to_vec(&mut output)),
+    );
+    group.bench_function("serialize_replies", |b| b.iter(|| send(&mut output)));
+    group.bench_function("serialize_replies_to_vec", |b| {
+        b.iter(|| to_vec(&mut output))
+    });
+}
+
+fn echo(c: &mut Criterion) {
+    let requests: Vec<_> = (0..BATCH)
+        .map(|i| {
+            let echo = format!("Please echo {}", i);
+            message("c1", "n0", i, None, EchoPayload::Echo { echo })
+        })
+        .collect();
+    let mut node: EchoNode = test_harness::init("n0", &["n0"]).expect("initializing the echo node");
+    let events: Vec<Event<EchoPayload>> = requests.iter().cloned().map(Event::Message).collect();
+    let replies = test_harness::drive(&mut node, events.clone()).expect("driving the echo node");
+
+    let mut group = c.benchmark_group("echo");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    bench_serde(&mut group, "echo", &requests, &replies);
+    group.bench_function("step", |b| {
+        b.iter_batched(
+            || events.clone(),
+            |events| test_harness::drive(&mut node, events).expect("driving the echo node"),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn broadcast(c: &mut Criterion) {
+    let nodes = ["n0", "n1", "n2", "n3", "n4"];
+    // Client broadcasts, gossip batches from peers and reads, the mix a node sees mid-run
+    let requests: Vec<Message<BroadcastPayload>> = (0..BATCH)
+        .map(|i| match i % 4 {
+            0 | 1 => message(
+                "c1",
+                "n0",
+                i,
+                None,
+                BroadcastPayload::Broadcast { message: i as i64 },
+            ),
+            2 => {
+                let messages = (i as i64..i as i64 + 16).collect();
+                message(
+                    nodes[1 + i % 4],
+                    "n0",
+                    i,
+                    None,
+                    BroadcastPayload::BroadcastBatch { messages },
+                )
+            }
+            _ => message("c2", "n0", i, None, BroadcastPayload::Read { from: None }),
+        })
+        .collect();
+    let mut node: BroadcastNode =
+        test_harness::init("n0", &nodes).expect("initializing the broadcast node");
+    let events: Vec<Event<BroadcastPayload, InjectedPayload>> =
+        requests.iter().cloned().map(Event::Message).collect();
+    let replies =
+        test_harness::drive(&mut node, events.clone()).expect("driving the broadcast node");
+
+    let mut group = c.benchmark_group("broadcast");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    bench_serde(&mut group, "broadcast", &requests, &replies);
+    // The node already holds every value, so each pass does the same work: acks, and reads of the full set
+    group.bench_function("step", |b| {
+        b.iter_batched(
+            || events.clone(),
+            |events| test_harness::drive(&mut node, events).expect("driving the broadcast node"),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn kafka(c: &mut Criterion) {
+    let keys: Vec<String> = (0..8).map(|k| k.to_string()).collect();
+    let offsets =
+        |i: usize| -> HashMap<String, usize> { keys.iter().map(|key| (key.clone(), i)).collect() };
+    let requests: Vec<Message<KafkaPayload>> = (0..BATCH)
+        .map(|i| {
+            let payload = match i % 3 {
+                0 => KafkaPayload::Send {
+                    key: keys[i % keys.len()].clone(),
+                    msg: i as i64,
+                },
+                1 => KafkaPayload::Poll {
+                    offsets: offsets(i),
+                },
+                _ => KafkaPayload::CommitOffsets {
+                    offsets: offsets(i),
+                },
+            };
+            message("c1", "n0", i, None, payload)
+        })
+        .collect();
+    let replies: Vec<Message<KafkaPayload>> = (0..BATCH)
+        .map(|i| {
+            let payload = match i % 3 {
+                0 => KafkaPayload::SendOk { offset: i },
+                1 => KafkaPayload::PollOk {
+                    msgs: keys
+                        .iter()
+                        .map(|key| {
+                            (
+                                key.clone(),
+                                (i..i + 10).map(|offset| (offset, offset as i64)).collect(),
+                            )
+                        })
+                        .collect(),
+                },
+                _ => KafkaPayload::CommitOffsetsOk {},
+            };
+            message("n0", "c1", BATCH + i, Some(i), payload)
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("kafka");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    bench_serde(&mut group, "kafka", &requests, &replies);
+    group.finish();
+}
+
+criterion_group!(benches, echo, broadcast, kafka);
+criterion_main!(benches);
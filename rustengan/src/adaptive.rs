@@ -0,0 +1,89 @@
+use anyhow::{bail, Context};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Defaults for the GOSSIP_INTERVAL_FLOOR_MS, GOSSIP_INTERVAL_CEILING_MS and GOSSIP_INTERVAL_STEP_MS env vars
+const FLOOR_MS: u64 = 50;
+const CEILING_MS: u64 = 1000;
+const STEP_MS: u64 = 50;
+
+/*
+Gossip interval that follows the churn, turned on with GOSSIP_ADAPTIVE. After each tick the node reports how many
+values its neighbors are still missing: while anything is outstanding the interval shrinks by `step` towards `floor`,
+so a burst spreads quickly, and once everything has converged it grows by `step` towards `ceiling`, so a quiet cluster
+stops spending messages on ticks with nothing to send. The ticker thread reads the current value before each wait,
+so a change takes effect from the next tick.
+*/
+#[derive(Debug, Clone)]
+pub struct AdaptiveInterval {
+    floor_ms: u64,
+    ceiling_ms: u64,
+    step_ms: u64,
+    current_ms: Arc<AtomicU64>, // Shared with the ticker thread
+}
+
+impl AdaptiveInterval {
+    // Starts at `start`, clamped to [floor, ceiling]
+    pub fn new(start: Duration, floor: Duration, ceiling: Duration, step: Duration) -> Self {
+        let (floor_ms, ceiling_ms) = (floor.as_millis() as u64, ceiling.as_millis() as u64);
+        AdaptiveInterval {
+            floor_ms,
+            ceiling_ms,
+            step_ms: step.as_millis() as u64,
+            current_ms: Arc::new(AtomicU64::new(
+                (start.as_millis() as u64).clamp(floor_ms, ceiling_ms),
+            )),
+        }
+    }
+
+    // None unless GOSSIP_ADAPTIVE is set; `start` is the fixed interval it would otherwise be
+    pub fn from_env(start: Duration) -> anyhow::Result<Option<Self>> {
+        if std::env::var_os("GOSSIP_ADAPTIVE").is_none() {
+            return Ok(None);
+        }
+        let floor = env_ms("GOSSIP_INTERVAL_FLOOR_MS", FLOOR_MS)?;
+        let ceiling = env_ms("GOSSIP_INTERVAL_CEILING_MS", CEILING_MS)?;
+        let step = env_ms("GOSSIP_INTERVAL_STEP_MS", STEP_MS)?;
+        if floor == 0 || step == 0 || floor > ceiling {
+            bail!(
+                "Adaptive gossip needs 0 < GOSSIP_INTERVAL_FLOOR_MS ({}) <= GOSSIP_INTERVAL_CEILING_MS ({}) and a \
+                 positive GOSSIP_INTERVAL_STEP_MS ({})",
+                floor,
+                ceiling,
+                step
+            );
+        }
+        Ok(Some(AdaptiveInterval::new(
+            start,
+            Duration::from_millis(floor),
+            Duration::from_millis(ceiling),
+            Duration::from_millis(step),
+        )))
+    }
+
+    pub fn current(&self) -> Duration {
+        Duration::from_millis(self.current_ms.load(Ordering::Relaxed))
+    }
+
+    // Moves the interval one step given how many values are still outstanding across neighbors, returning the new one
+    pub fn adjust(&self, outstanding: usize) -> Duration {
+        let current = self.current_ms.load(Ordering::Relaxed);
+        let next = if outstanding > 0 {
+            current.saturating_sub(self.step_ms).max(self.floor_ms)
+        } else {
+            (current + self.step_ms).min(self.ceiling_ms)
+        };
+        self.current_ms.store(next, Ordering::Relaxed);
+        Duration::from_millis(next)
+    }
+}
+
+fn env_ms(name: &str, default: u64) -> anyhow::Result<u64> {
+    match std::env::var(name) {
+        Ok(ms) => ms
+            .parse()
+            .with_context(|| format!("Invalid {}: {}", name, ms)),
+        Err(_) => Ok(default),
+    }
+}
@@ -0,0 +1,6 @@
+use rustengan::nodes::counter::{CounterNode, CounterPayload, InjectedPayload};
+use rustengan::run_node;
+
+fn main() -> anyhow::Result<()> {
+    run_node::<CounterNode, CounterPayload, InjectedPayload>()
+}
@@ -0,0 +1,8 @@
+use rustengan::dedup::Dedup;
+use rustengan::nodes::kafka::{InjectedPayload, KafkaNode, KafkaPayload};
+use rustengan::run_node;
+
+fn main() -> anyhow::Result<()> {
+    // A retried send must not append the same message twice, so repeated requests get their original reply back
+    run_node::<Dedup<KafkaNode>, KafkaPayload, InjectedPayload>()
+}
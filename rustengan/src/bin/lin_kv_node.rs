@@ -0,0 +1,7 @@
+use rustengan::kv::KvPayload;
+use rustengan::nodes::lin_kv::{InjectedPayload, LinKvNode};
+use rustengan::run_node;
+
+fn main() -> anyhow::Result<()> {
+    run_node::<LinKvNode, KvPayload, InjectedPayload>()
+}
@@ -0,0 +1,6 @@
+use rustengan::nodes::txn::{InjectedPayload, ReadUncommittedTxnNode, TxnPayload};
+use rustengan::run_node;
+
+fn main() -> anyhow::Result<()> {
+    run_node::<ReadUncommittedTxnNode, TxnPayload, InjectedPayload>()
+}
@@ -0,0 +1,6 @@
+use rustengan::nodes::txn::{InjectedPayload, TxnNode, TxnPayload};
+use rustengan::run_node;
+
+fn main() -> anyhow::Result<()> {
+    run_node::<TxnNode, TxnPayload, InjectedPayload>()
+}
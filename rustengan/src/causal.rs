@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/*
+Vector clock: per node, how many messages from that node a replica has seen (or, attached to a message, how many
+its sender had seen when it sent it). A missing entry counts as 0.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock {
+    pub counts: HashMap<String, u64>,
+}
+
+impl VectorClock {
+    pub fn get(&self, node: &str) -> u64 {
+        self.counts.get(node).copied().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, node: &str) -> u64 {
+        let count = self.counts.entry(node.to_string()).or_default();
+        *count += 1;
+        *count
+    }
+
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, count) in &other.counts {
+            let entry = self.counts.entry(node.clone()).or_default();
+            *entry = (*entry).max(*count);
+        }
+    }
+
+    // Whether every event this clock has seen was also seen by `other`, and `other` has seen more
+    pub fn happened_before(&self, other: &VectorClock) -> bool {
+        self.counts
+            .iter()
+            .all(|(node, count)| *count <= other.get(node))
+            && other
+                .counts
+                .iter()
+                .any(|(node, count)| *count > self.get(node))
+    }
+}
+
+/*
+Causal delivery: messages stamped with their sender's vector clock are held until everything that causally precedes
+them has been delivered. A message from `sender` is deliverable once it's the next one from that sender and we've
+delivered at least as much from every other node as the sender had when it sent it.
+*/
+#[derive(Debug, Clone)]
+pub struct CausalBuffer<T> {
+    delivered: VectorClock,
+    pending: Vec<(String, VectorClock, T)>,
+}
+
+impl<T> Default for CausalBuffer<T> {
+    fn default() -> Self {
+        CausalBuffer {
+            delivered: VectorClock::default(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<T> CausalBuffer<T> {
+    pub fn delivered(&self) -> &VectorClock {
+        &self.delivered
+    }
+
+    // Counts a message we're sending ourselves (it's delivered locally right away) and returns the clock to attach
+    pub fn stamp(&mut self, node: &str) -> VectorClock {
+        self.delivered.increment(node);
+        self.delivered.clone()
+    }
+
+    /*
+    Takes a message from `sender`, returning everything that became deliverable, in causal order: the message itself
+    if its dependencies are met, followed by any held messages it unblocked. Redelivered messages are dropped.
+    */
+    pub fn receive(&mut self, sender: &str, clock: VectorClock, item: T) -> Vec<T> {
+        let seq = clock.get(sender);
+        let held = self
+            .pending
+            .iter()
+            .any(|(from, pending, _)| from == sender && pending.get(sender) == seq);
+        if seq <= self.delivered.get(sender) || held {
+            return Vec::new();
+        }
+        self.pending.push((sender.to_string(), clock, item));
+
+        let mut released = Vec::new();
+        while let Some(index) = self
+            .pending
+            .iter()
+            .position(|(from, clock, _)| self.deliverable(from, clock))
+        {
+            let (from, _, item) = self.pending.remove(index);
+            self.delivered.increment(&from);
+            released.push(item);
+        }
+        released
+    }
+
+    fn deliverable(&self, sender: &str, clock: &VectorClock) -> bool {
+        clock.get(sender) == self.delivered.get(sender) + 1
+            && clock
+                .counts
+                .iter()
+                .all(|(node, count)| node == sender || *count <= self.delivered.get(node))
+    }
+}
+
+/*
+Per-origin FIFO delivery: every origin numbers the messages it sends 1, 2, 3, ..., and a message is held until every
+earlier one from the same origin has been delivered. Unlike CausalBuffer, messages from different origins never
+wait on each other. Delivered messages are kept per origin, in the order they were sent, for reads.
+*/
+#[derive(Debug, Clone)]
+pub struct FifoBuffer<T> {
+    // Sorted by origin, so reading them all out gives the same order on every node
+    delivered: BTreeMap<String, Vec<T>>,
+    pending: HashMap<String, BTreeMap<u64, T>>,
+}
+
+impl<T> Default for FifoBuffer<T> {
+    fn default() -> Self {
+        FifoBuffer {
+            delivered: BTreeMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<T> FifoBuffer<T> {
+    // Delivers a message we're sending ourselves and returns the seq to send it with
+    pub fn stamp(&mut self, origin: &str, item: T) -> u64 {
+        let delivered = self.delivered.entry(origin.to_string()).or_default();
+        delivered.push(item);
+        delivered.len() as u64
+    }
+
+    /*
+    Takes message `seq` from `origin`, returning everything that became deliverable, in order: nothing if an earlier
+    message from `origin` is still missing, otherwise this one followed by any held ones it unblocked. Redelivered
+    messages are dropped.
+    */
+    pub fn receive(&mut self, origin: &str, seq: u64, item: T) -> Vec<T>
+    where
+        T: Clone,
+    {
+        let delivered = self.delivered.entry(origin.to_string()).or_default();
+        if seq <= delivered.len() as u64 {
+            return Vec::new();
+        }
+        let pending = self.pending.entry(origin.to_string()).or_default();
+        pending.entry(seq).or_insert(item);
+
+        let mut released = Vec::new();
+        while let Some(item) = pending.remove(&(delivered.len() as u64 + 1)) {
+            delivered.push(item.clone());
+            released.push(item);
+        }
+        released
+    }
+
+    // Every delivered message, origin by origin, each origin's in the order it sent them
+    pub fn delivered(&self) -> impl Iterator<Item = &T> {
+        self.delivered.values().flatten()
+    }
+}
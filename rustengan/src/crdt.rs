@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/*
+Grow-only counter CRDT: one count per node, and each node only ever bumps its own entry.
+Replicas merge by taking the element-wise max, so exchanging full states in any order, any number of times
+(including after a partition heals) converges every replica to the same total.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GCounter {
+    pub counts: HashMap<String, u64>,
+}
+
+impl GCounter {
+    pub fn increment(&mut self, node: &str, delta: u64) {
+        *self.counts.entry(node.to_string()).or_default() += delta;
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn merge(&mut self, counts: &HashMap<String, u64>) {
+        for (node, count) in counts {
+            let entry = self.counts.entry(node.clone()).or_default();
+            *entry = (*entry).max(*count);
+        }
+    }
+}
+
+/* PN-counter CRDT: a pair of G-counters, one for increments and one for decrements */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PnCounter {
+    pub positive: GCounter,
+    pub negative: GCounter,
+}
+
+impl PnCounter {
+    // Room for `nodes` nodes' entries on each side, so they're not reallocated as nodes first show up
+    pub fn with_capacity(nodes: usize) -> Self {
+        let counter = || GCounter {
+            counts: HashMap::with_capacity(nodes),
+        };
+        PnCounter {
+            positive: counter(),
+            negative: counter(),
+        }
+    }
+
+    pub fn increment(&mut self, node: &str, delta: i64) {
+        if delta >= 0 {
+            self.positive.increment(node, delta.unsigned_abs());
+        } else {
+            self.negative.increment(node, delta.unsigned_abs());
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.positive.value() as i64 - self.negative.value() as i64
+    }
+
+    pub fn merge(&mut self, other: &PnCounter) {
+        self.positive.merge(&other.positive.counts);
+        self.negative.merge(&other.negative.counts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::{Rng, XorShift};
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    // A replica that has seen a random handful of increments and decrements from each node
+    fn replica(rng: &mut XorShift) -> PnCounter {
+        let mut counter = PnCounter::default();
+        for _ in 0..rng.next_u64() % 8 {
+            let node = NODES[(rng.next_u64() % 3) as usize];
+            counter.increment(node, (rng.next_u64() % 21) as i64 - 10);
+        }
+        counter
+    }
+
+    fn merged(a: &PnCounter, b: &PnCounter) -> PnCounter {
+        let mut merged = a.clone();
+        merged.merge(b);
+        merged
+    }
+
+    // Merging has to be commutative, associative and idempotent for replicas to agree however gossip reaches them
+    #[test]
+    fn merge_obeys_the_crdt_laws() {
+        let mut rng = XorShift::new(7);
+        for _ in 0..200 {
+            let [a, b, c] = [(); 3].map(|_| replica(&mut rng));
+            assert_eq!(merged(&a, &b), merged(&b, &a), "not commutative");
+            assert_eq!(
+                merged(&merged(&a, &b), &c),
+                merged(&a, &merged(&b, &c)),
+                "not associative"
+            );
+            assert_eq!(merged(&a, &a), a, "not idempotent");
+            assert_eq!(
+                merged(&merged(&a, &b), &b),
+                merged(&a, &b),
+                "not idempotent"
+            );
+            // Each side of a PN-counter is a G-counter in its own right
+            let mut positive = a.positive.clone();
+            positive.merge(&b.positive.counts);
+            assert_eq!(positive, merged(&a, &b).positive);
+        }
+    }
+
+    /*
+    Two replicas that start from the same state and then take increments and decrements from different nodes have to
+    agree after merging, with every decrement still counted.
+    */
+    #[test]
+    fn concurrent_decrements_survive_a_merge() {
+        let mut base = PnCounter::default();
+        base.increment("n0", 10);
+        let mut left = base.clone();
+        let mut right = base;
+        left.increment("n0", 5);
+        left.increment("n0", -3);
+        right.increment("n1", -4);
+        right.increment("n1", 2);
+
+        let left_then_right = merged(&left, &right);
+        assert_eq!(left_then_right, merged(&right, &left));
+        assert_eq!(left_then_right.value(), 10 + 5 - 3 - 4 + 2);
+        assert_eq!(left_then_right.negative.value(), 7);
+    }
+}
@@ -0,0 +1,299 @@
+use crate::{
+    error_code, step_node, ErrorPayload, Event, Message, MessageBody, MsgId, Node, NodeId,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+use std::io::Write;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/*
+Soft deadline on message handlers, set with the HANDLER_DEADLINE_MS env var (off when unset). A handler stuck in a
+CAS loop or waiting on a slow proxy would otherwise leave its client waiting until Maelstrom gives up on the request,
+which fails the test with nothing to say why. With a deadline, a watchdog thread answers the client with a timeout
+error (code 0, so it may retry) once the handler has run that long without replying. The handler itself is never
+interrupted: it runs to completion and its state changes stand, only its late reply is dropped, so the client never
+sees two answers to one request.
+*/
+
+// How often the watchdog compares the running handler against its deadline
+const WATCH_INTERVAL: Duration = Duration::from_millis(5);
+
+pub fn from_env() -> anyhow::Result<Option<Duration>> {
+    match std::env::var("HANDLER_DEADLINE_MS") {
+        Ok(ms) => {
+            let ms = ms
+                .parse()
+                .with_context(|| format!("Invalid HANDLER_DEADLINE_MS: {}", ms))?;
+            Ok(Some(Duration::from_millis(ms)))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/* The request whose handler is running right now */
+struct Watched {
+    node_id: NodeId,
+    client: NodeId,
+    msg_id: MsgId,
+    deadline: Instant,
+    replied: bool,
+    timed_out: bool,
+}
+
+// Handlers and the watchdog both write replies to the request under this lock, so exactly one of them gets to answer
+// the client
+type SharedWatch = Arc<Mutex<Option<Watched>>>;
+
+pub struct Watchdog {
+    deadline: Duration,
+    watched: SharedWatch,
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    // Starts the watchdog thread, which writes its timeout replies to `output`
+    pub fn spawn(deadline: Duration, mut output: impl Write + Send + 'static) -> Self {
+        let watched: SharedWatch = Arc::default();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread_watched = Arc::clone(&watched);
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(WATCH_INTERVAL) {
+                let mut watched = thread_watched.lock().unwrap();
+                let Some(request) = watched.as_mut() else {
+                    continue;
+                };
+                if request.replied || request.timed_out || Instant::now() < request.deadline {
+                    continue;
+                }
+                request.timed_out = true;
+                let reply = Message {
+                    src: request.node_id.clone(),
+                    dest: request.client.clone(),
+                    body: MessageBody {
+                        msg_id: None,
+                        in_reply_to: Some(request.msg_id),
+                        payload: ErrorPayload::Error {
+                            code: error_code::TIMEOUT,
+                            text: format!("handler ran past its {:?} deadline", deadline),
+                        },
+                    },
+                };
+                if reply.send(&mut output).is_err() || output.flush().is_err() {
+                    break;
+                }
+            }
+        });
+        Watchdog {
+            deadline,
+            watched,
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /*
+    step_node, with the handler for a message that expects a reply watched against the deadline. Replies go to
+    `output` as usual unless the watchdog has already timed the request out. Returns whether it did.
+    */
+    pub fn step<N, Payload, InjectedPayload>(
+        &self,
+        node: &mut N,
+        event: Event<Payload, InjectedPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<bool>
+    where
+        N: Node<Payload, InjectedPayload>,
+    {
+        if let Event::Message(input) = &event {
+            *self.watched.lock().unwrap() = input.body.msg_id.map(|msg_id| Watched {
+                node_id: input.dest.clone(),
+                client: input.src.clone(),
+                msg_id,
+                deadline: Instant::now() + self.deadline,
+                replied: false,
+                timed_out: false,
+            });
+        }
+        let mut guarded = Guarded {
+            inner: output,
+            watched: &self.watched,
+        };
+        let result = step_node(node, event, &mut guarded);
+        let timed_out = self
+            .watched
+            .lock()
+            .unwrap()
+            .take()
+            .is_some_and(|request| request.timed_out);
+        result.map(|()| timed_out)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/* Just enough of an outgoing message to tell whether it answers the watched request */
+#[derive(Deserialize)]
+struct ReplyHeader {
+    dest: NodeId,
+    body: ReplyHeaderBody,
+}
+
+#[derive(Deserialize)]
+struct ReplyHeaderBody {
+    in_reply_to: Option<MsgId>,
+}
+
+/* The node's output while a watched handler runs: marks the request replied to, or drops the reply if it's too late */
+struct Guarded<'a, W> {
+    inner: W,
+    watched: &'a SharedWatch,
+}
+
+impl<W: Write> Write for Guarded<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut watched = self.watched.lock().unwrap();
+        if let Some(request) = watched.as_mut().filter(|request| !request.replied) {
+            // Every write is one whole message (see Message::send)
+            let answers = serde_json::from_slice::<ReplyHeader>(buf).is_ok_and(|header| {
+                header.dest == request.client && header.body.in_reply_to == Some(request.msg_id)
+            });
+            if answers && request.timed_out {
+                return Ok(buf.len());
+            }
+            request.replied |= answers;
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Serialize;
+
+    /* Payloads for a deliberately slow node */
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum SlowPayload {
+        Sleep { ms: u64 },
+        SleepOk {},
+    }
+
+    /* Takes as long as it's told to before answering, and counts the sleeps it finished */
+    struct SlowNode {
+        net: crate::Network,
+        finished: usize,
+    }
+
+    impl Node<SlowPayload> for SlowNode {
+        fn from_init(
+            net: crate::Network,
+            _inject: mpsc::Sender<Event<SlowPayload>>,
+        ) -> anyhow::Result<Self> {
+            Ok(SlowNode { net, finished: 0 })
+        }
+
+        fn step(
+            &mut self,
+            event: Event<SlowPayload>,
+            output: &mut impl Write,
+        ) -> anyhow::Result<()> {
+            let Event::Message(input) = event else {
+                return Ok(());
+            };
+            if let SlowPayload::Sleep { ms } = input.body.payload {
+                thread::sleep(Duration::from_millis(ms));
+                self.finished += 1;
+                self.net.reply(&input, SlowPayload::SleepOk {}, output)?;
+            }
+            Ok(())
+        }
+    }
+
+    /* Output both the node and the watchdog write to */
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn take_lines(&self) -> anyhow::Result<Vec<Message<serde_json::Value>>> {
+            let bytes = std::mem::take(&mut *self.0.lock().unwrap());
+            serde_json::Deserializer::from_slice(&bytes)
+                .into_iter()
+                .map(|message| message.context("Unparseable output line"))
+                .collect()
+        }
+    }
+
+    /*
+    With a 50ms deadline, a handler that sleeps 300ms has to get its client a single timeout error and nothing else,
+    while still finishing (the node counts it), and a fast handler's reply has to go out untouched.
+    */
+    #[test]
+    fn slow_handler_gets_one_timeout_error() -> anyhow::Result<()> {
+        let output = SharedBuffer::default();
+        let watchdog = Watchdog::spawn(Duration::from_millis(50), output.clone());
+        let mut node: SlowNode = crate::test_harness::init("n0", &["n0"])?;
+        let sleep = |msg_id, ms| {
+            Event::Message(Message {
+                src: NodeId::from("c1"),
+                dest: NodeId::from("n0"),
+                body: MessageBody {
+                    msg_id: Some(MsgId(msg_id)),
+                    in_reply_to: None,
+                    payload: SlowPayload::Sleep { ms },
+                },
+            })
+        };
+
+        let timed_out = watchdog.step(&mut node, sleep(1, 300), &mut output.clone())?;
+        let replies = output.take_lines()?;
+        let answered_with_timeout = matches!(
+            replies.as_slice(),
+            [reply] if reply.body.in_reply_to == Some(MsgId(1))
+                && reply.body.payload["type"] == "error"
+                && reply.body.payload["code"] == error_code::TIMEOUT
+        );
+        assert!(timed_out, "a 300ms handler didn't time out");
+        assert!(answered_with_timeout, "a 300ms handler got {:?}", replies);
+        assert_eq!(node.finished, 1);
+
+        let timed_out = watchdog.step(&mut node, sleep(2, 0), &mut output.clone())?;
+        let replies = output.take_lines()?;
+        assert!(!timed_out, "an instant handler timed out");
+        assert!(
+            replies.len() == 1 && replies[0].body.payload["type"] == "sleep_ok",
+            "an instant handler got {:?}",
+            replies
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use crate::{Event, MsgId, Network, Node, NodeId};
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::sync::mpsc;
+
+// How many (src, msg_id) pairs Dedup remembers before evicting the least recently used
+const CAPACITY: usize = 4096;
+
+/*
+Wraps a node so requests are handled at most once. The replies a request produced are cached under its
+(src, msg_id); if the same request arrives again (a client or peer retrying after a lost ack) the cached replies are
+resent instead of running step again, so side effects like a Kafka append aren't repeated. A request that isn't
+answered in the step that handled it (e.g. one proxied to another node) stays cached as unanswered: retries of it are
+dropped while the original is still in progress, and its reply is cached whenever a later step sends it.
+Only the most recent CAPACITY requests are remembered; a retry older than that is handled as a new request.
+*/
+pub struct Dedup<N> {
+    inner: N,
+    cache: HashMap<(NodeId, MsgId), Cached>,
+    // Every use of a cached request, oldest first, stamped so that entries for a request used again since are skipped
+    recency: VecDeque<(u64, (NodeId, MsgId))>,
+    next_stamp: u64,
+    unanswered: usize, // Cached requests still waiting for their reply
+}
+
+struct Cached {
+    replies: Vec<Vec<u8>>,
+    answered: bool,
+    stamp: u64, // Of its latest entry in recency
+}
+
+impl<N> Dedup<N> {
+    fn touch(&mut self, key: &(NodeId, MsgId)) {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        if let Some(cached) = self.cache.get_mut(key) {
+            cached.stamp = stamp;
+        }
+        self.recency.push_back((stamp, key.clone()));
+        // Drop the stale entries once they outnumber the live ones, so repeated retries can't grow the queue forever
+        if self.recency.len() > 2 * CAPACITY {
+            let cache = &self.cache;
+            self.recency
+                .retain(|(stamp, key)| cache.get(key).is_some_and(|c| c.stamp == *stamp));
+        }
+    }
+
+    fn remember(&mut self, key: (NodeId, MsgId)) {
+        if self.cache.len() >= CAPACITY {
+            self.evict_oldest();
+        }
+        self.cache.insert(
+            key.clone(),
+            Cached {
+                replies: Vec::new(),
+                answered: false,
+                stamp: 0,
+            },
+        );
+        self.unanswered += 1;
+        self.touch(&key);
+    }
+
+    fn evict_oldest(&mut self) {
+        while let Some((stamp, key)) = self.recency.pop_front() {
+            if self.cache.get(&key).is_some_and(|c| c.stamp == stamp) {
+                return self.forget(&key);
+            }
+        }
+    }
+
+    fn forget(&mut self, key: &(NodeId, MsgId)) {
+        if let Some(forgotten) = self.cache.remove(key) {
+            self.unanswered -= usize::from(!forgotten.answered);
+        }
+    }
+
+    // Caches whichever of `written` answer a cached request
+    fn record_replies(&mut self, written: Vec<Vec<u8>>) {
+        for message in written {
+            let Some(cached) = reply_key(&message).and_then(|key| self.cache.get_mut(&key)) else {
+                continue;
+            };
+            cached.replies.push(message);
+            if !cached.answered {
+                cached.answered = true;
+                self.unanswered -= 1;
+            }
+        }
+    }
+}
+
+impl<N, Payload, InjectedPayload> Node<Payload, InjectedPayload> for Dedup<N>
+where
+    N: Node<Payload, InjectedPayload>,
+{
+    fn from_init(
+        net: Network,
+        inject: mpsc::Sender<Event<Payload, InjectedPayload>>,
+    ) -> anyhow::Result<Self> {
+        Ok(Dedup {
+            inner: N::from_init(net, inject)?,
+            cache: HashMap::new(),
+            recency: VecDeque::new(),
+            next_stamp: 0,
+            unanswered: 0,
+        })
+    }
+
+    fn step(
+        &mut self,
+        event: Event<Payload, InjectedPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let key = match &event {
+            Event::Message(input) => input.body.msg_id.map(|msg_id| (input.src.clone(), msg_id)),
+            Event::Injected(_) | Event::Eof => None,
+        };
+        if let Some(key) = &key {
+            if let Some(cached) = self.cache.get(key) {
+                // Nothing to resend yet if the original is still waiting for its reply
+                for reply in &cached.replies {
+                    output.write_all(reply)?;
+                }
+                self.touch(key);
+                return Ok(());
+            }
+        }
+        // Only a request, or a step that might answer an earlier one, needs its messages looked at
+        if key.is_none() && self.unanswered == 0 {
+            return self.inner.step(event, output);
+        }
+        if let Some(key) = &key {
+            self.remember(key.clone());
+        }
+
+        // Everything still goes straight out (step may block on an rpc whose request has to leave first);
+        // the tee just keeps a copy of each message so the replies can be picked out afterwards
+        let mut tee = Tee {
+            output,
+            written: Vec::new(),
+        };
+        let result = self.inner.step(event, &mut tee);
+        let written = tee.written;
+        self.record_replies(written);
+        // step_node answers a failed request itself, so forget it and let a retry run it again
+        if let (Err(_), Some(key)) = (&result, &key) {
+            self.forget(key);
+        }
+        result
+    }
+}
+
+// The (src, msg_id) of the request a serialized message answers, if it's a reply
+fn reply_key(message: &[u8]) -> Option<(NodeId, MsgId)> {
+    let message = serde_json::from_slice::<serde_json::Value>(message).ok()?;
+    let dest = message["dest"].as_str()?;
+    let in_reply_to = message["body"]["in_reply_to"].as_u64()?;
+    Some((NodeId::from(dest), MsgId(in_reply_to as usize)))
+}
+
+/* Writer that passes everything through while keeping a copy of each write (one whole message, see Message::send) */
+struct Tee<'a, W> {
+    output: &'a mut W,
+    written: Vec<Vec<u8>>,
+}
+
+impl<W: Write> Write for Tee<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write_all(buf)?;
+        self.written.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_harness, Message, MessageBody};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum AskPayload {
+        Ask {},
+        AskOk { handled: usize },
+    }
+
+    /* Answers each ask only once it's injected an Answer, like a node waiting on a peer; counts what it handled */
+    struct LateNode {
+        net: Network,
+        handled: usize,
+        waiting: Vec<Message<AskPayload>>,
+    }
+
+    struct Answer;
+
+    impl Node<AskPayload, Answer> for LateNode {
+        fn from_init(
+            net: Network,
+            _inject: mpsc::Sender<Event<AskPayload, Answer>>,
+        ) -> anyhow::Result<Self> {
+            Ok(LateNode {
+                net,
+                handled: 0,
+                waiting: Vec::new(),
+            })
+        }
+
+        fn step(
+            &mut self,
+            event: Event<AskPayload, Answer>,
+            output: &mut impl Write,
+        ) -> anyhow::Result<()> {
+            match event {
+                Event::Message(input) => {
+                    self.handled += 1;
+                    self.waiting.push(input);
+                }
+                Event::Injected(Answer) => {
+                    for input in std::mem::take(&mut self.waiting) {
+                        let handled = self.handled;
+                        self.net
+                            .reply(&input, AskPayload::AskOk { handled }, output)?;
+                    }
+                }
+                Event::Eof => {}
+            }
+            Ok(())
+        }
+    }
+
+    fn ask(src: &str, msg_id: usize) -> Event<AskPayload, Answer> {
+        Event::Message(Message {
+            src: NodeId::from(src),
+            dest: NodeId::from("n0"),
+            body: MessageBody {
+                msg_id: Some(MsgId(msg_id)),
+                in_reply_to: None,
+                payload: AskPayload::Ask {},
+            },
+        })
+    }
+
+    /*
+    A retry that arrives while the original is still unanswered must be dropped rather than handled again, and once
+    the late reply goes out a further retry has to get that same reply back.
+    */
+    #[test]
+    fn retries_of_a_late_reply_run_once() -> anyhow::Result<()> {
+        let mut node: Dedup<LateNode> = test_harness::init("n0", &["n0"])?;
+        let early = test_harness::drive(&mut node, vec![ask("c1", 1), ask("c1", 1)])?;
+        assert!(
+            early.is_empty(),
+            "answered {:?} before the reply was ready",
+            early
+        );
+        assert_eq!(node.inner.handled, 1);
+
+        let late = test_harness::drive(&mut node, vec![Event::Injected(Answer)])?;
+        let retried = test_harness::drive(&mut node, vec![ask("c1", 1)])?;
+        assert_eq!(node.inner.handled, 1);
+        let payloads = |replies: Vec<Message<AskPayload>>| -> Vec<_> {
+            replies
+                .into_iter()
+                .map(|reply| reply.body.payload)
+                .collect()
+        };
+        assert_eq!(payloads(late), [AskPayload::AskOk { handled: 1 }]);
+        assert_eq!(payloads(retried), [AskPayload::AskOk { handled: 1 }]);
+        Ok(())
+    }
+
+    // Past CAPACITY the least recently used request is forgotten: one retried along the way is kept, the next
+    // oldest goes
+    #[test]
+    fn least_recently_used_request_is_evicted() -> anyhow::Result<()> {
+        let mut node: Dedup<LateNode> = test_harness::init("n0", &["n0"])?;
+        let mut events: Vec<_> = (0..CAPACITY).map(|msg_id| ask("c1", msg_id)).collect();
+        events.push(ask("c1", 0));
+        events.push(ask("c1", CAPACITY));
+        test_harness::drive(&mut node, events)?;
+        assert_eq!(node.inner.handled, CAPACITY + 1);
+
+        test_harness::drive(&mut node, vec![ask("c1", 0)])?;
+        assert_eq!(
+            node.inner.handled,
+            CAPACITY + 1,
+            "the retried request was evicted"
+        );
+        test_harness::drive(&mut node, vec![ask("c1", 1)])?;
+        assert_eq!(
+            node.inner.handled,
+            CAPACITY + 2,
+            "the oldest request wasn't evicted"
+        );
+        Ok(())
+    }
+}
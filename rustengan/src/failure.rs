@@ -0,0 +1,108 @@
+use crate::NodeId;
+
+use anyhow::Context;
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Defaults for the HEARTBEAT_INTERVAL_MS and HEARTBEAT_MAX_MISSED env vars
+pub const HEARTBEAT_INTERVAL_MS: u64 = 500;
+pub const MAX_MISSED: u32 = 3;
+
+/*
+Timeout-counting failure detector. Every heartbeat the node pings each peer; a ping still unanswered by the next
+heartbeat counts as a miss, and a peer with max_missed misses in a row is suspected. Any pong from a peer clears its
+misses, so a suspected peer is reinstated as soon as it answers again. It only ever suspects, never knows: a peer
+behind a partition or a slow link looks exactly like a dead one.
+*/
+#[derive(Debug, Clone)]
+pub struct FailureDetector {
+    max_missed: u32,
+    peers: HashMap<NodeId, PeerState>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PeerState {
+    awaiting_pong: bool,
+    missed: u32,
+}
+
+impl FailureDetector {
+    pub fn new<'a>(peers: impl IntoIterator<Item = &'a NodeId>, max_missed: u32) -> Self {
+        FailureDetector {
+            max_missed: max_missed.max(1),
+            peers: peers
+                .into_iter()
+                .map(|peer| (peer.clone(), PeerState::default()))
+                .collect(),
+        }
+    }
+
+    // Configured from HEARTBEAT_MAX_MISSED, like run_node's other env vars
+    pub fn from_env<'a>(peers: impl IntoIterator<Item = &'a NodeId>) -> anyhow::Result<Self> {
+        Ok(FailureDetector::new(peers, max_missed()?))
+    }
+
+    /*
+    Starts a heartbeat round: every ping from the last round that's still unanswered counts as a miss, and every
+    peer is due a new ping. Returns the peers to ping, sorted, and the ones that became suspected this round.
+    */
+    pub fn heartbeat(&mut self) -> (Vec<NodeId>, Vec<NodeId>) {
+        let mut newly_suspected = Vec::new();
+        for (peer, state) in &mut self.peers {
+            if state.awaiting_pong {
+                state.missed += 1;
+                if state.missed == self.max_missed {
+                    newly_suspected.push(peer.clone());
+                }
+            }
+            state.awaiting_pong = true;
+        }
+        let mut ping: Vec<NodeId> = self.peers.keys().cloned().collect();
+        ping.sort();
+        newly_suspected.sort();
+        (ping, newly_suspected)
+    }
+
+    // Records a pong from `peer`, returning true if that reinstates a suspected peer
+    pub fn pong(&mut self, peer: &NodeId) -> bool {
+        let max_missed = self.max_missed;
+        let Some(state) = self.peers.get_mut(peer) else {
+            return false;
+        };
+        let reinstated = state.missed >= max_missed;
+        *state = PeerState::default();
+        reinstated
+    }
+
+    pub fn is_suspected(&self, peer: &NodeId) -> bool {
+        self.peers
+            .get(peer)
+            .is_some_and(|state| state.missed >= self.max_missed)
+    }
+
+    pub fn suspected_count(&self) -> usize {
+        self.peers
+            .keys()
+            .filter(|peer| self.is_suspected(peer))
+            .count()
+    }
+}
+
+pub fn max_missed() -> anyhow::Result<u32> {
+    match std::env::var("HEARTBEAT_MAX_MISSED") {
+        Ok(max) => max
+            .parse()
+            .with_context(|| format!("Invalid HEARTBEAT_MAX_MISSED: {}", max)),
+        Err(_) => Ok(MAX_MISSED),
+    }
+}
+
+pub fn heartbeat_interval() -> anyhow::Result<Duration> {
+    let ms = match std::env::var("HEARTBEAT_INTERVAL_MS") {
+        Ok(ms) => ms
+            .parse()
+            .with_context(|| format!("Invalid HEARTBEAT_INTERVAL_MS: {}", ms))?,
+        Err(_) => HEARTBEAT_INTERVAL_MS,
+    };
+    Ok(Duration::from_millis(ms))
+}
@@ -0,0 +1,312 @@
+use crate::causal::VectorClock;
+use crate::kv::KvPayload;
+use crate::nodes::*;
+use crate::txn::TxnOp;
+use crate::{ErrorPayload, Init, InitPayload, Message, MessageBody, MsgId, NodeId};
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+
+/*
+Golden check for the wire format: one sample message per payload variant, serialized the way a node writes it and
+compared byte for byte against fixtures/payloads.jsonl. Editing a payload enum (renaming a field, changing an
+Option's handling, reordering struct fields) changes these bytes, so schema drift shows up here before a grader
+rejects it. If a change is intended, update the fixture with the lines the failure reports.
+*/
+const FIXTURE: &str = include_str!("../fixtures/payloads.jsonl");
+
+// Builds the sample lines, checking each one also parses back into the same bytes. add is generic over the payload,
+// so this also proves Message<P> works with every node's internally tagged payload enum flattened into its body
+struct Samples {
+    lines: Vec<String>,
+}
+
+impl Samples {
+    fn add<P: Serialize + DeserializeOwned>(
+        &mut self,
+        msg_id: Option<usize>,
+        in_reply_to: Option<usize>,
+        payload: P,
+    ) -> anyhow::Result<()> {
+        let message = Message {
+            src: NodeId::from("n1"),
+            dest: NodeId::from("c1"),
+            body: MessageBody {
+                msg_id: msg_id.map(MsgId),
+                in_reply_to: in_reply_to.map(MsgId),
+                payload,
+            },
+        };
+        let line = serde_json::to_string(&message)?;
+        let parsed: Message<P> =
+            serde_json::from_str(&line).with_context(|| format!("{} doesn't parse back", line))?;
+        let reparsed = serde_json::to_string(&parsed)?;
+        if reparsed != line {
+            bail!("{} doesn't round-trip, it comes back as {}", line, reparsed);
+        }
+        self.lines.push(line);
+        Ok(())
+    }
+
+    fn request<P: Serialize + DeserializeOwned>(&mut self, payload: P) -> anyhow::Result<()> {
+        self.add(Some(1), None, payload)
+    }
+
+    fn reply<P: Serialize + DeserializeOwned>(&mut self, payload: P) -> anyhow::Result<()> {
+        self.add(Some(2), Some(1), payload)
+    }
+}
+
+// Maps in samples only ever get one entry, since HashMap iteration order would make the bytes unstable
+fn one<K: From<&'static str> + Eq + std::hash::Hash, V>(
+    key: &'static str,
+    value: V,
+) -> HashMap<K, V> {
+    HashMap::from([(K::from(key), value)])
+}
+
+fn render() -> anyhow::Result<Vec<String>> {
+    let mut samples = Samples { lines: Vec::new() };
+
+    samples.request(InitPayload::Init(Init {
+        node_id: NodeId::from("n1"),
+        node_ids: vec![NodeId::from("n1"), NodeId::from("n2")],
+    }))?;
+    // The one reply without a msg_id of its own
+    samples.add(None, Some(1), InitPayload::InitOk {})?;
+    samples.reply(ErrorPayload::Error {
+        code: crate::error_code::NOT_SUPPORTED,
+        text: "n1 does not support this message type".to_string(),
+    })?;
+
+    let echo = "hello".to_string();
+    samples.request(echo::EchoPayload::Echo { echo: echo.clone() })?;
+    samples.reply(echo::EchoPayload::EchoOk { echo })?;
+
+    samples.request(unique_id::UniqueIDPayload::Generate {})?;
+    samples.reply(unique_id::UniqueIDPayload::GenerateOk {
+        id: "n1-1".to_string(),
+    })?;
+
+    // Maelstrom's integer values, which is what the wire format has to match
+    type BroadcastPayload = broadcast::BroadcastPayload<i64>;
+    samples.request(BroadcastPayload::Broadcast { message: 7 })?;
+    samples.reply(BroadcastPayload::BroadcastOk {})?;
+    samples.request(BroadcastPayload::BroadcastBatch {
+        messages: vec![7, 8],
+    })?;
+    samples.reply(BroadcastPayload::BroadcastBatchOk {})?;
+    samples.request(BroadcastPayload::Read { from: None })?;
+    samples.reply(BroadcastPayload::ReadOk {
+        messages: vec![7, 8],
+        next: None,
+    })?;
+    samples.request(BroadcastPayload::Read { from: Some(2) })?;
+    samples.reply(BroadcastPayload::ReadOk {
+        messages: vec![9, 10],
+        next: Some(4),
+    })?;
+    samples.request(BroadcastPayload::Topology {
+        topology: one("n1", vec![NodeId::from("n2")]),
+    })?;
+    samples.reply(BroadcastPayload::TopologyOk {})?;
+    samples.request(BroadcastPayload::CausalBroadcast {
+        message: 7,
+        clock: VectorClock {
+            counts: one("n1", 3),
+        },
+    })?;
+    samples.reply(BroadcastPayload::CausalBroadcastOk {})?;
+    samples.request(BroadcastPayload::FifoBroadcast {
+        message: 7,
+        origin: NodeId::from("n1"),
+        seq: 2,
+    })?;
+    samples.reply(BroadcastPayload::FifoBroadcastOk {})?;
+    samples.request(BroadcastPayload::Ping {})?;
+    samples.reply(BroadcastPayload::Pong {})?;
+
+    use counter::CounterPayload;
+    samples.request(CounterPayload::Add { delta: 3 })?;
+    samples.reply(CounterPayload::AddOk {})?;
+    samples.request(CounterPayload::Read {})?;
+    samples.reply(CounterPayload::ReadOk { value: 3 })?;
+    samples.request(CounterPayload::CounterGossip {
+        counts: one("n1", 5),
+        negative_counts: one("n1", 2),
+    })?;
+
+    use kafka::KafkaPayload;
+    samples.request(KafkaPayload::Send {
+        key: "k1".to_string(),
+        msg: 9,
+    })?;
+    samples.reply(KafkaPayload::SendOk { offset: 4 })?;
+    samples.request(KafkaPayload::Poll {
+        offsets: one("k1", 4),
+    })?;
+    samples.reply(KafkaPayload::PollOk {
+        msgs: one("k1", vec![(4, 9), (5, 10)]),
+    })?;
+    samples.request(KafkaPayload::CommitOffsets {
+        offsets: one("k1", 4),
+    })?;
+    samples.reply(KafkaPayload::CommitOffsetsOk {})?;
+    samples.request(KafkaPayload::ListCommittedOffsets {
+        keys: vec!["k1".to_string()],
+    })?;
+    samples.reply(KafkaPayload::ListCommittedOffsetsOk {
+        offsets: one("k1", 4),
+    })?;
+    samples.request(KafkaPayload::Ping {})?;
+    samples.reply(KafkaPayload::Pong {})?;
+
+    let txn = vec![
+        TxnOp::Read {
+            key: 1,
+            value: None,
+        },
+        TxnOp::Write { key: 1, value: 6 },
+    ];
+    let txn_ok = vec![
+        TxnOp::Read {
+            key: 1,
+            value: Some(5),
+        },
+        TxnOp::Write { key: 1, value: 6 },
+    ];
+    samples.request(txn::TxnPayload::Txn { txn })?;
+    samples.reply(txn::TxnPayload::TxnOk { txn: txn_ok })?;
+    samples.request(txn::TxnPayload::ReplicateWrite {
+        key: 1,
+        value: 6,
+        version: (3, 1),
+    })?;
+
+    samples.request(KvPayload::Read { key: "k1".into() })?;
+    samples.reply(KvPayload::ReadOk { value: 5.into() })?;
+    samples.request(KvPayload::Write {
+        key: "k1".into(),
+        value: 5.into(),
+    })?;
+    samples.reply(KvPayload::WriteOk {})?;
+    samples.request(KvPayload::Cas {
+        key: "k1".into(),
+        from: 5.into(),
+        to: 6.into(),
+        create_if_not_exists: true,
+    })?;
+    samples.reply(KvPayload::CasOk {})?;
+    samples.reply(KvPayload::Error {
+        code: crate::error_code::KEY_DOES_NOT_EXIST,
+        text: "key does not exist".to_string(),
+    })?;
+
+    Ok(samples.lines)
+}
+
+// Compares every sample against the fixture, reporting each line that differs
+#[test]
+fn payloads_match_fixture() -> anyhow::Result<()> {
+    let actual = render()?;
+    let expected: Vec<&str> = FIXTURE.lines().collect();
+    let mut mismatches = Vec::new();
+    for i in 0..actual.len().max(expected.len()) {
+        let (actual, expected) = (actual.get(i).map(String::as_str), expected.get(i).copied());
+        if actual != expected {
+            mismatches.push(format!(
+                "line {}:\n  expected {}\n  actual   {}",
+                i + 1,
+                expected.unwrap_or("<nothing>"),
+                actual.unwrap_or("<nothing>")
+            ));
+        }
+    }
+    if !mismatches.is_empty() {
+        bail!(
+            "Wire format differs from fixtures/payloads.jsonl:\n{}",
+            mismatches.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/*
+Broadcast and counter both answer a read with "read_ok", shaped differently ({messages} vs {value}). One enum can't
+hold both: serde's internally tagged enums pick the variant by tag alone, so a second variant renamed to "read_ok"
+would never be chosen. Each node has its own payload enum instead, and this checks neither node's read_ok is
+mistaken for the other's: the samples above already round-trip each one, so here each has to be refused by the other
+enum rather than misread with a field missing.
+*/
+#[test]
+fn read_ok_shapes_stay_apart() -> anyhow::Result<()> {
+    let broadcast = r#"{"type":"read_ok","messages":[7,8]}"#;
+    let counter = r#"{"type":"read_ok","value":3}"#;
+    if let Ok(parsed) = serde_json::from_str::<counter::CounterPayload>(broadcast) {
+        bail!("Broadcast's {} parses as counter's {:?}", broadcast, parsed);
+    }
+    if let Ok(parsed) = serde_json::from_str::<broadcast::BroadcastPayload<i64>>(counter) {
+        bail!("Counter's {} parses as broadcast's {:?}", counter, parsed);
+    }
+    Ok(())
+}
+
+/*
+Pinned key -> owner mappings for a five-node cluster, plus a few FNV-1a reference values. Ownership has to survive
+compiler upgrades and crate edits unchanged (see KeyHash), so any change to these is a bug, not a fixture update.
+Keys include kafka's plain keys and lin-kv's JSON-encoded ones.
+*/
+const PINNED_HASHES: [(&str, u64); 3] = [
+    ("", 0xcbf2_9ce4_8422_2325),
+    ("a", 0xaf63_dc4c_8601_ec8c),
+    ("foobar", 0x8594_4171_f739_67e8),
+];
+const PINNED_OWNERS: [(&str, &str); 10] = [
+    ("0", "n4"),
+    ("1", "n3"),
+    ("2", "n1"),
+    ("k1", "n3"),
+    ("k2", "n0"),
+    ("k3", "n1"),
+    ("orders", "n2"),
+    ("payments", "n2"),
+    ("\"1\"", "n0"),
+    ("\"k1\"", "n2"),
+];
+
+#[test]
+fn key_owners_stay_pinned() -> anyhow::Result<()> {
+    for (key, expected) in PINNED_HASHES {
+        let actual = crate::stable_hash(key);
+        if actual != expected {
+            bail!(
+                "key hash check failed: {:?} hashed to {:#x}, not FNV-1a's {:#x}",
+                key,
+                actual,
+                expected
+            );
+        }
+    }
+    let nodes = ["n0", "n1", "n2", "n3", "n4"];
+    let net = crate::Network::new(Init {
+        node_id: NodeId::from("n0"),
+        node_ids: nodes.iter().map(|id| NodeId::from(*id)).collect(),
+    })?;
+    let mismatches: Vec<String> = PINNED_OWNERS
+        .iter()
+        .filter(|(key, owner)| net.key_owner(key).as_str() != *owner)
+        .map(|(key, owner)| {
+            format!(
+                "{:?} is owned by {}, not {}",
+                key,
+                net.key_owner(key),
+                owner
+            )
+        })
+        .collect();
+    if !mismatches.is_empty() {
+        bail!("key hash check failed:\n  {}", mismatches.join("\n  "));
+    }
+    Ok(())
+}
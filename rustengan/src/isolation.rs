@@ -0,0 +1,138 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/*
+Keeps one panicking handler from taking the whole node down. step_node runs each handler under catch_unwind; a panic
+(an unwrap on malformed data, an out-of-bounds index) is logged to stderr, the request it was handling gets a crash
+error (code 13), and the node goes on to the next message with whatever state the handler left behind. Locks shared
+with a handler are taken with `lock`, which ignores poisoning, so a panic while one was held doesn't turn every later
+use of it into another panic.
+*/
+
+// Runs `step`, turning a panic into Err with the panic's message
+pub(crate) fn catch_panic(
+    step: impl FnOnce() -> anyhow::Result<()>,
+) -> Result<anyhow::Result<()>, String> {
+    panic::catch_unwind(AssertUnwindSafe(step)).map_err(|panic| panic_text(&*panic))
+}
+
+fn panic_text(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(text), _) => text.to_string(),
+        (_, Some(text)) => text.clone(),
+        _ => "non-string panic payload".to_string(),
+    }
+}
+
+// Locks `mutex` even if a panicking handler poisoned it; the data is whatever that handler left
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error_code, Event, Message, MessageBody, MsgId, Node, NodeId};
+
+    use serde::{Deserialize, Serialize};
+    use std::io::Write;
+    use std::sync::{mpsc, Arc};
+
+    /* Payloads for a node that panics on demand */
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum FragilePayload {
+        Add { delta: u64 },
+        AddOk { total: u64 },
+        // Panics while holding the lock on the total
+        Explode {},
+        Error { code: u64, text: String },
+    }
+
+    struct FragileNode {
+        net: crate::Network,
+        total: Arc<Mutex<u64>>,
+    }
+
+    impl Node<FragilePayload> for FragileNode {
+        fn from_init(
+            net: crate::Network,
+            _inject: mpsc::Sender<Event<FragilePayload>>,
+        ) -> anyhow::Result<Self> {
+            Ok(FragileNode {
+                net,
+                total: Arc::default(),
+            })
+        }
+
+        fn step(
+            &mut self,
+            event: Event<FragilePayload>,
+            output: &mut impl Write,
+        ) -> anyhow::Result<()> {
+            let Event::Message(input) = event else {
+                return Ok(());
+            };
+            match input.body.payload {
+                FragilePayload::Add { delta } => {
+                    let total = {
+                        let mut total = lock(&self.total);
+                        *total += delta;
+                        *total
+                    };
+                    self.net
+                        .reply(&input, FragilePayload::AddOk { total }, output)
+                }
+                FragilePayload::Explode {} => {
+                    let _total = lock(&self.total);
+                    panic!("asked to explode")
+                }
+                FragilePayload::AddOk { .. } | FragilePayload::Error { .. } => Ok(()),
+            }
+        }
+    }
+
+    /*
+    A node whose handler panics on `explode` (with the node's one lock held) has to answer that request with a crash
+    error, and still answer the adds before and after it, the last one with a total that counts the first.
+    */
+    #[test]
+    fn panicking_handler_gets_a_crash_error() -> anyhow::Result<()> {
+        let mut node: FragileNode = crate::test_harness::init("n0", &["n0"])?;
+        let request = |msg_id, payload| {
+            Event::Message(Message {
+                src: NodeId::from("c1"),
+                dest: NodeId::from("n0"),
+                body: MessageBody {
+                    msg_id: Some(MsgId(msg_id)),
+                    in_reply_to: None,
+                    payload,
+                },
+            })
+        };
+        let replies = crate::test_harness::drive(
+            &mut node,
+            vec![
+                request(1, FragilePayload::Add { delta: 2 }),
+                request(2, FragilePayload::Explode {}),
+                request(3, FragilePayload::Add { delta: 3 }),
+            ],
+        )?;
+        let replies: Vec<serde_json::Value> = replies
+            .into_iter()
+            .map(|reply| serde_json::to_value(&reply.body))
+            .collect::<Result<_, _>>()?;
+        let answered = matches!(
+            replies.as_slice(),
+            [first, crashed, last]
+                if first["total"] == 2
+                    && crashed["in_reply_to"] == 2
+                    && crashed["code"] == error_code::CRASH
+                    && last["total"] == 5
+        );
+        assert!(answered, "add, explode, add got {:?}", replies);
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{error_code, Network, NodeId, RpcTimeout};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+// How long a kv call waits for the service before giving up with a timeout error
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+// CAS can keep losing under heavy contention; cas_update gives up after this many and lets the client retry
+pub const MAX_CAS_ATTEMPTS: usize = 20;
+// The pause after cas_update's first lost race; it doubles with every further one, up to MAX_CAS_BACKOFF
+const CAS_BACKOFF: Duration = Duration::from_micros(500);
+const MAX_CAS_BACKOFF: Duration = Duration::from_millis(16);
+
+/* Requests and replies understood by Maelstrom's seq-kv / lin-kv / lww-kv services */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KvPayload {
+    Read {
+        key: serde_json::Value,
+    },
+    ReadOk {
+        value: serde_json::Value,
+    },
+    Write {
+        key: serde_json::Value,
+        value: serde_json::Value,
+    },
+    WriteOk {},
+    Cas {
+        key: serde_json::Value,
+        from: serde_json::Value,
+        to: serde_json::Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk {},
+    Error {
+        code: u64,
+        text: String,
+    },
+}
+
+/* Error replies from a kv service, typed so callers can match on them instead of on the text */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvError {
+    KeyDoesNotExist(String),
+    PreconditionFailed(String),
+    Other { code: u64, text: String },
+}
+
+impl KvError {
+    fn from_reply(code: u64, text: String) -> Self {
+        match code {
+            error_code::KEY_DOES_NOT_EXIST => KvError::KeyDoesNotExist(text),
+            error_code::PRECONDITION_FAILED => KvError::PreconditionFailed(text),
+            code => KvError::Other { code, text },
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::KeyDoesNotExist(text) => write!(f, "key does not exist: {}", text),
+            KvError::PreconditionFailed(text) => write!(f, "precondition failed: {}", text),
+            KvError::Other { code, text } => write!(f, "kv error {}: {}", code, text),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+/* How a cas_update ended */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CasOutcome<V> {
+    Updated { from: V, to: V },
+    // The update function had nothing to change; holds the value it was given
+    Unchanged(V),
+    // Every attempt lost its CAS to another writer
+    Contended,
+}
+
+/*
+Blocking client for one of Maelstrom's kv services. Each call sends a request through Network::rpc and waits
+for the service's reply, so it can be used straight from a node's step function.
+Error replies come back as a KvError inside the anyhow::Error; use err.downcast_ref::<KvError>() to inspect them.
+A call that gets no reply within the client's timeout fails with KvError::Other { code: error_code::TIMEOUT, .. }.
+*/
+#[derive(Debug, Clone)]
+pub struct KvClient {
+    service: NodeId,
+    timeout: Duration,
+}
+
+impl KvClient {
+    pub fn new(service: impl Into<NodeId>) -> Self {
+        KvClient {
+            service: service.into(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Sequentially consistent store
+    pub fn seq() -> Self {
+        KvClient::new("seq-kv")
+    }
+
+    // Linearizable store
+    pub fn lin() -> Self {
+        KvClient::new("lin-kv")
+    }
+
+    pub fn read<V: DeserializeOwned>(
+        &self,
+        net: &Network,
+        key: impl Serialize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<V> {
+        let key = serde_json::to_value(key).context("Failed to serialize kv key")?;
+        match self.call(net, KvPayload::Read { key }, output)? {
+            KvPayload::ReadOk { value } => {
+                serde_json::from_value(value).context("kv value has an unexpected type")
+            }
+            other => anyhow::bail!("Unexpected reply to kv read: {:?}", other),
+        }
+    }
+
+    pub fn write(
+        &self,
+        net: &Network,
+        key: impl Serialize,
+        value: impl Serialize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let payload = KvPayload::Write {
+            key: serde_json::to_value(key).context("Failed to serialize kv key")?,
+            value: serde_json::to_value(value).context("Failed to serialize kv value")?,
+        };
+        match self.call(net, payload, output)? {
+            KvPayload::WriteOk {} => Ok(()),
+            other => anyhow::bail!("Unexpected reply to kv write: {:?}", other),
+        }
+    }
+
+    pub fn cas(
+        &self,
+        net: &Network,
+        key: impl Serialize,
+        from: impl Serialize,
+        to: impl Serialize,
+        create_if_not_exists: bool,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let payload = KvPayload::Cas {
+            key: serde_json::to_value(key).context("Failed to serialize kv key")?,
+            from: serde_json::to_value(from).context("Failed to serialize kv value")?,
+            to: serde_json::to_value(to).context("Failed to serialize kv value")?,
+            create_if_not_exists,
+        };
+        match self.call(net, payload, output)? {
+            KvPayload::CasOk {} => Ok(()),
+            other => anyhow::bail!("Unexpected reply to kv cas: {:?}", other),
+        }
+    }
+
+    /*
+    Read-modify-write of one key: reads it (as `default` if it doesn't exist yet), has `update` compute the new value
+    from that, and CASes from the one to the other, creating the key if it was missing. A CAS that loses to another
+    writer is retried from a fresh read after a jittered pause that doubles each time, at most MAX_CAS_ATTEMPTS times
+    in all. `update` returning None leaves the key alone.
+    */
+    pub fn cas_update<V, F>(
+        &self,
+        net: &Network,
+        key: impl Serialize,
+        default: V,
+        output: &mut impl Write,
+        mut update: F,
+    ) -> anyhow::Result<CasOutcome<V>>
+    where
+        V: Serialize + DeserializeOwned + Clone,
+        F: FnMut(&V) -> Option<V>,
+    {
+        let key = serde_json::to_value(key).context("Failed to serialize kv key")?;
+        let mut backoff = CAS_BACKOFF;
+        for attempt in 0..MAX_CAS_ATTEMPTS {
+            if attempt > 0 {
+                thread::sleep(jittered(backoff, net.next_random()));
+                backoff = (backoff * 2).min(MAX_CAS_BACKOFF);
+            }
+            let current = match self.read(net, &key, output) {
+                Ok(current) => Some(current),
+                Err(e) if matches!(e.downcast_ref(), Some(KvError::KeyDoesNotExist(_))) => None,
+                Err(e) => return Err(e),
+            };
+            let exists = current.is_some();
+            let current = current.unwrap_or_else(|| default.clone());
+            let Some(new) = update(&current) else {
+                return Ok(CasOutcome::Unchanged(current));
+            };
+            // create_if_not_exists only when the read found nothing, so `from` is always checked against a real value
+            match self.cas(net, &key, &current, &new, !exists, output) {
+                Ok(()) => {
+                    return Ok(CasOutcome::Updated {
+                        from: current,
+                        to: new,
+                    })
+                }
+                Err(e)
+                    if matches!(
+                        e.downcast_ref(),
+                        Some(KvError::PreconditionFailed(_) | KvError::KeyDoesNotExist(_))
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(CasOutcome::Contended)
+    }
+
+    fn call(
+        &self,
+        net: &Network,
+        payload: KvPayload,
+        output: &mut impl Write,
+    ) -> anyhow::Result<KvPayload> {
+        let reply = net
+            .rpc::<KvPayload, KvPayload>(&self.service, payload, self.timeout, output)
+            .map_err(|e| match e.downcast::<RpcTimeout>() {
+                Ok(timeout) => KvError::from_reply(error_code::TIMEOUT, timeout.to_string()).into(),
+                Err(e) => e,
+            })?;
+        match reply.body.payload {
+            KvPayload::Error { code, text } => Err(KvError::from_reply(code, text).into()),
+            payload => Ok(payload),
+        }
+    }
+}
+
+// Somewhere between half of `backoff` and all of it, so writers that lost the same race don't retry in lockstep
+fn jittered(backoff: Duration, random: u64) -> Duration {
+    backoff / 2 + backoff.mul_f64((random % 1024) as f64 / 2048.0)
+}
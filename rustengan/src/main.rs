@@ -0,0 +1,52 @@
+use rustengan::dedup::Dedup;
+use rustengan::nodes::*;
+use rustengan::run_node_with_args;
+
+const USAGE: &str =
+    "usage: rustengan [echo|unique-ids|broadcast|counter|kafka|lin-kv|txn|read-uncommitted-txn] \
+                     [--replay <path>] [--trace <path>] [--node-count-hint <n>]";
+
+/*
+One binary for every challenge: the first argument picks the node to run (echo if it's missing or is already a flag),
+and the remaining arguments are the usual node flags
+*/
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    let mode = match args.peek() {
+        Some(arg) if !arg.starts_with("--") => args.next().unwrap(),
+        _ => "echo".to_string(),
+    };
+    match mode.as_str() {
+        "echo" => run_node_with_args::<echo::EchoNode, echo::EchoPayload, ()>(args),
+        "unique-ids" => {
+            run_node_with_args::<unique_id::UniqueIDNode, unique_id::UniqueIDPayload, ()>(args)
+        }
+        "broadcast" => run_node_with_args::<
+            broadcast::BroadcastNode,
+            broadcast::BroadcastPayload,
+            broadcast::InjectedPayload,
+        >(args),
+        "counter" => run_node_with_args::<
+            counter::CounterNode,
+            counter::CounterPayload,
+            counter::InjectedPayload,
+        >(args),
+        "kafka" => {
+            run_node_with_args::<Dedup<kafka::KafkaNode>, kafka::KafkaPayload, kafka::InjectedPayload>(
+                args,
+            )
+        }
+        "lin-kv" => {
+            run_node_with_args::<lin_kv::LinKvNode, rustengan::kv::KvPayload, lin_kv::InjectedPayload>(
+                args,
+            )
+        }
+        "txn" => run_node_with_args::<txn::TxnNode, txn::TxnPayload, txn::InjectedPayload>(args),
+        "read-uncommitted-txn" => {
+            run_node_with_args::<txn::ReadUncommittedTxnNode, txn::TxnPayload, txn::InjectedPayload>(
+                args,
+            )
+        }
+        _ => anyhow::bail!("Unknown mode {:?}; {}", mode, USAGE),
+    }
+}
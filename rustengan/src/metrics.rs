@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// Each power-of-two range is split into 2^SUB_BUCKET_BITS linear buckets, so recorded values keep ~6% precision
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/*
+HDR-style histogram of u64 values (log-linear buckets): fixed size, O(1) to record, and quantiles come back as the
+upper edge of the bucket they fall in, so they're never understated.
+*/
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: vec![0; ((64 - SUB_BUCKET_BITS as usize) + 1) * SUB_BUCKETS as usize],
+            total: 0,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        self.counts[bucket(value)] += 1;
+        self.total += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    // Smallest bucket edge that at least `quantile` (0.0..=1.0) of the recorded values are at or below
+    pub fn quantile(&self, quantile: f64) -> u64 {
+        let target = ((quantile * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return bucket_upper_edge(i).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+fn bucket(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - SUB_BUCKET_BITS;
+    let sub_bucket = (value >> shift) & (SUB_BUCKETS - 1);
+    ((shift as u64 + 1) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+fn bucket_upper_edge(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    let sub_bucket = bucket % SUB_BUCKETS;
+    // The top bucket's edge is 2^64 - 1, one past what the shift can hold in a u64
+    let edge = ((SUB_BUCKETS + sub_bucket + 1) as u128) << shift;
+    (edge - 1).min(u64::MAX as u128) as u64
+}
+
+/* Throughput, step latency and per-type message counts for a run; run_node keeps one when MAELSTROM_METRICS is set */
+#[derive(Debug)]
+pub struct Metrics {
+    started: Instant,
+    latency_nanos: Histogram,
+    // Inbound messages by body.type, sorted so the summary lists them in the same order every run
+    by_type: BTreeMap<String, u64>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            started: Instant::now(),
+            latency_nanos: Histogram::default(),
+            by_type: BTreeMap::new(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record(&mut self, msg_type: &str, latency: Duration) {
+        self.latency_nanos
+            .record(latency.as_nanos().try_into().unwrap_or(u64::MAX));
+        *self.by_type.entry(msg_type.to_string()).or_default() += 1;
+    }
+
+    pub fn summary(&self) -> String {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let latency = &self.latency_nanos;
+        let micros = |nanos: u64| nanos as f64 / 1000.0;
+        let by_type: Vec<String> = self
+            .by_type
+            .iter()
+            .map(|(msg_type, count)| format!("{}={}", msg_type, count))
+            .collect();
+        format!(
+            "Metrics: {} messages in {:.2}s ({:.1} msgs/sec); step latency p50={:.1}us p99={:.1}us max={:.1}us; by type: {}",
+            latency.count(),
+            elapsed,
+            latency.count() as f64 / elapsed,
+            micros(latency.quantile(0.5)),
+            micros(latency.quantile(0.99)),
+            micros(latency.max()),
+            by_type.join(" "),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every value lands in a bucket whose upper edge is at or above it and within the bucket width (1/SUB_BUCKETS)
+    #[test]
+    fn buckets_bound_their_values() {
+        let mut values: Vec<u64> = (0..SUB_BUCKETS * 2).collect();
+        for bits in SUB_BUCKET_BITS..64 {
+            let power = 1u64 << bits;
+            values.extend([power - 1, power, power + 1]);
+        }
+        values.push(u64::MAX);
+        for value in values {
+            let edge = bucket_upper_edge(bucket(value));
+            assert!(
+                edge >= value && edge - value <= value / SUB_BUCKETS,
+                "{} landed in a bucket with edge {}",
+                value,
+                edge
+            );
+        }
+        assert_eq!(bucket(u64::MAX), Histogram::default().counts.len() - 1);
+    }
+
+    /*
+    Values below SUB_BUCKETS get a bucket each, so their quantiles are exact; 99 values of 1000 and one of u64::MAX
+    put p50 and p99 at the top of 1000's bucket and leave the max exact.
+    */
+    #[test]
+    fn quantiles_fall_on_bucket_edges() {
+        let mut small = Histogram::default();
+        (1..=10).for_each(|value| small.record(value));
+        assert_eq!(
+            (small.quantile(0.5), small.quantile(0.99), small.max()),
+            (5, 10, 10)
+        );
+
+        let mut large = Histogram::default();
+        (0..99).for_each(|_| large.record(1000));
+        large.record(u64::MAX);
+        let top_of_1000 = bucket_upper_edge(bucket(1000));
+        assert!((1000..=1000 + 1000 / SUB_BUCKETS).contains(&top_of_1000));
+        assert_eq!(large.quantile(0.5), top_of_1000);
+        assert_eq!(large.quantile(0.99), top_of_1000);
+        assert_eq!(large.quantile(1.0), u64::MAX);
+        assert_eq!(large.max(), u64::MAX);
+    }
+
+    // The summary lists how many messages of each type came in
+    #[test]
+    fn summary_counts_each_type() {
+        let mut metrics = Metrics::default();
+        for msg_type in ["read", "broadcast", "read"] {
+            metrics.record(msg_type, Duration::from_micros(10));
+        }
+        let summary = metrics.summary();
+        assert!(summary.starts_with("Metrics: 3 messages"), "{}", summary);
+        assert!(
+            summary.ends_with("by type: broadcast=1 read=2"),
+            "{}",
+            summary
+        );
+    }
+}
@@ -0,0 +1,1875 @@
+use crate::adaptive::AdaptiveInterval;
+use crate::causal::{CausalBuffer, FifoBuffer, VectorClock};
+use crate::failure::{self, FailureDetector};
+use crate::rate_limit::TokenBucket;
+use crate::*;
+
+use anyhow::bail;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::io::Write;
+use std::sync::mpsc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum BroadcastPayload<V = i64> {
+    Broadcast {
+        message: V,
+    },
+    BroadcastOk {},
+    // Node-to-node gossip: carries every value the receiver hasn't acked yet in one frame
+    BroadcastBatch {
+        messages: Vec<V>,
+    },
+    BroadcastBatchOk {},
+    // `from` asks for one page of the sorted set starting at that index (BROADCAST_READ_PAGE_SIZE), an extension for
+    // our own drivers; Maelstrom never sends it and always gets the whole set
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<usize>,
+    },
+    ReadOk {
+        messages: Vec<V>,
+        // Where the next page starts, on a paged read that didn't reach the end
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next: Option<usize>,
+    },
+    Topology {
+        topology: HashMap<NodeId, Vec<NodeId>>,
+    },
+    TopologyOk {},
+    // Sent by a value's origin straight to every peer in causal mode, stamped with the origin's vector clock
+    CausalBroadcast {
+        message: V,
+        clock: VectorClock,
+    },
+    CausalBroadcastOk {},
+    // Sent by a value's origin straight to every peer in fifo mode, numbered in the order the origin got them
+    FifoBroadcast {
+        message: V,
+        origin: NodeId,
+        seq: u64,
+    },
+    FifoBroadcastOk {},
+    // Heartbeat, so the failure detector can tell which peers are still answering
+    Ping {},
+    Pong {},
+    // Not part of Maelstrom: asks for a snapshot of the node's state (debug feature only)
+    #[cfg(feature = "debug")]
+    Debug {},
+    #[cfg(feature = "debug")]
+    DebugOk {
+        #[serde(flatten)]
+        snapshot: DebugSnapshot,
+    },
+}
+
+/* Events the node schedules for itself */
+#[derive(Debug, Clone)]
+pub enum InjectedPayload {
+    Gossip,    // Anti-entropy round, every gossip interval
+    Heartbeat, // Ping every peer, every heartbeat interval
+    // A gossip round right now that sends every neighbor everything it's missing, ignoring backoff, fan-out and the
+    // rate limit; only tests and simulations schedule it, so Maelstrom can't trigger it
+    GossipNow,
+}
+
+// Default anti-entropy interval; override with the GOSSIP_INTERVAL_MS env var
+const GOSSIP_INTERVAL_MS: u64 = 300;
+// How long to wait for a neighbor to ack a batch before giving up on it (the next gossip round resends anyway)
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+// Longest a neighbor that keeps not acking is skipped between retransmits, in gossip ticks
+const MAX_BACKOFF_TICKS: u32 = 16;
+
+/*
+Retransmit backoff for one neighbor. Every unacked gossip round doubles how many ticks we skip before the next one
+(capped at MAX_BACKOFF_TICKS, with jitter so neighbors cut off by the same partition don't all retry on the same tick).
+Hearing from the neighbor at all, whether an ack or its own gossip, clears it.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Backoff {
+    failures: u32,
+    skip_ticks: u32,
+}
+
+/*
+How the node picks its gossip neighbors when a Topology message arrives; set with the BROADCAST_TOPOLOGY env var
+("provided", "star", "tree:<fanout>", "hypercube", or "all"). Maelstrom's provided grid is often a poor trade-off
+between latency and messages-per-op, so the other strategies ignore it and overlay their own graph on the sorted
+node_ids.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopologyStrategy {
+    UseProvided,
+    Star,      // Everyone talks to the first node, which talks to everyone
+    Tree(u32), // Balanced tree with the given fanout, rooted at the first node
+    // Neighbors differ from us in one bit of the index, so any node is at most log2(N) hops away; a square-ish grid
+    // when N isn't a power of two
+    Hypercube,
+    AllToAll,
+}
+
+impl std::str::FromStr for TopologyStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.split_once(':') {
+            None if s == "provided" => Ok(TopologyStrategy::UseProvided),
+            None if s == "star" => Ok(TopologyStrategy::Star),
+            None if s == "all" => Ok(TopologyStrategy::AllToAll),
+            None if s == "hypercube" => Ok(TopologyStrategy::Hypercube),
+            Some(("tree", fanout)) => match fanout.parse()? {
+                0 => bail!("Tree topology needs a fanout of at least 1"),
+                fanout => Ok(TopologyStrategy::Tree(fanout)),
+            },
+            _ => bail!("Unknown topology strategy: {}", s),
+        }
+    }
+}
+
+impl TopologyStrategy {
+    fn neighbors(&self, net: &Network, provided: &HashMap<NodeId, Vec<NodeId>>) -> Vec<NodeId> {
+        let nodes = net.sorted_node_ids();
+        match self.overlay(nodes.len(), net.node_index()) {
+            Some(indices) => indices.into_iter().map(|i| nodes[i].clone()).collect(),
+            None => provided
+                .get(&net.node_id)
+                .map(|listed| known_peers(net, listed))
+                .unwrap_or_default(),
+        }
+    }
+
+    // Indices of the neighbors of node `me` out of `count` sorted nodes; None for the provided topology
+    fn overlay(&self, count: usize, me: usize) -> Option<Vec<usize>> {
+        Some(match *self {
+            TopologyStrategy::UseProvided => return None,
+            TopologyStrategy::Star if me == 0 => (1..count).collect(),
+            TopologyStrategy::Star => vec![0],
+            TopologyStrategy::Tree(fanout) => {
+                let fanout = fanout as usize;
+                let parent = (me > 0).then(|| (me - 1) / fanout);
+                let children = (me * fanout + 1..=me * fanout + fanout).filter(|i| *i < count);
+                parent.into_iter().chain(children).collect()
+            }
+            TopologyStrategy::Hypercube if count.is_power_of_two() => (0..count.trailing_zeros())
+                .map(|bit| me ^ (1 << bit))
+                .collect(),
+            TopologyStrategy::Hypercube => {
+                // Rows of `width`, the last one possibly short; every node in it still has one above it
+                let width = (1..).find(|width| width * width >= count).unwrap_or(1);
+                let (row, column) = (me / width, me % width);
+                let up = (row > 0).then(|| me - width);
+                let down = Some(me + width).filter(|i| *i < count);
+                let left = (column > 0).then(|| me - 1);
+                let right = Some(me + 1).filter(|i| column + 1 < width && *i < count);
+                [up, down, left, right].into_iter().flatten().collect()
+            }
+            TopologyStrategy::AllToAll => (0..count).filter(|i| *i != me).collect(),
+        })
+    }
+}
+
+// The provided neighbors that are actually our peers. A self-loop is dropped quietly; an id that isn't in node_ids is
+// dropped with a warning, since gossip to it would never be acked and would back off forever
+fn known_peers(net: &Network, listed: &[NodeId]) -> Vec<NodeId> {
+    listed
+        .iter()
+        .filter(|neighbor| {
+            if !net.node_ids.contains(neighbor) {
+                eprintln!(
+                    "Ignoring unknown neighbor {} in the provided topology",
+                    neighbor
+                );
+                return false;
+            }
+            **neighbor != net.node_id
+        })
+        .cloned()
+        .collect()
+}
+
+/*
+How values spread; set with the BROADCAST_ORDERING env var ("unordered", the default, "causal", or "fifo").
+unordered gossips batches over the topology, so nodes can see values in any order.
+causal has each value's origin send it to every peer with a vector clock, and peers hold a value back until every
+value its origin had seen before it has been delivered, so a value never shows up in a read ahead of its causes.
+Anti-entropy batches would skip that check, so causal mode relies on retrying each send until it's acked instead.
+fifo works the same way but only keeps each origin's own values in order: the origin numbers them, a peer holds a
+value back until it has every earlier one from that origin, and reads list the values origin by origin in that order.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ordering {
+    Unordered,
+    Causal,
+    Fifo,
+}
+
+impl std::str::FromStr for Ordering {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "unordered" => Ok(Ordering::Unordered),
+            "causal" => Ok(Ordering::Causal),
+            "fifo" => Ok(Ordering::Fifo),
+            _ => bail!("Unknown ordering: {}", s),
+        }
+    }
+}
+
+/*
+Which values a Read returns; set with the BROADCAST_READ_MODE env var ("local", the default, or "quorum").
+local returns every value this node has seen. quorum only returns values a majority of the cluster is known to
+hold (us plus every neighbor that has sent or acked the value), i.e. the ones that would survive losing a minority.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    Local,
+    Quorum,
+}
+
+impl std::str::FromStr for ReadMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "local" => Ok(ReadMode::Local),
+            "quorum" => Ok(ReadMode::Quorum),
+            _ => bail!("Unknown read mode: {}", s),
+        }
+    }
+}
+
+/*
+A value the broadcast node can spread: anything hashable and ordered that travels as JSON (the order only keeps
+gossip batches the same from run to run, whatever the HashSet's seed). Maelstrom only ever broadcasts
+integers, hence the i64 default, but nothing except compaction cares what a value is. Compaction needs values that
+count up from 0, so a type only gets it by mapping onto that sequence; for any other type BROADCAST_COMPACT is a no-op.
+*/
+pub trait BroadcastValue:
+    Clone + Eq + Ord + Hash + Debug + Serialize + DeserializeOwned + Send + 'static
+{
+    // This value's position in the 0, 1, 2, ... sequence the compaction watermark covers, if it has one
+    fn sequence(&self) -> Option<i64> {
+        None
+    }
+
+    // The value at a position in that sequence, to read compacted values back out
+    fn from_sequence(_sequence: i64) -> Option<Self> {
+        None
+    }
+}
+
+impl BroadcastValue for i64 {
+    fn sequence(&self) -> Option<i64> {
+        Some(*self)
+    }
+
+    fn from_sequence(sequence: i64) -> Option<Self> {
+        Some(sequence)
+    }
+}
+
+impl BroadcastValue for String {}
+
+/*
+How a broadcast node is set up. from_init reads it from the environment (from_env); tests and simulations build one
+themselves, starting from Default, so they never have to touch the process environment.
+*/
+pub struct BroadcastConfig {
+    pub gossip_interval: Duration,               // GOSSIP_INTERVAL_MS
+    pub gossip_fanout: Option<usize>,            // GOSSIP_FANOUT
+    pub gossip_limiter: Option<TokenBucket>,     // GOSSIP_RATE_LIMIT and GOSSIP_BURST
+    pub gossip_pacing: Option<AdaptiveInterval>, // GOSSIP_ADAPTIVE and its floor, ceiling and step
+    pub compact: bool,                           // BROADCAST_COMPACT
+    pub topology_strategy: TopologyStrategy,     // BROADCAST_TOPOLOGY
+    pub read_mode: ReadMode,                     // BROADCAST_READ_MODE
+    pub ordering: Ordering,                      // BROADCAST_ORDERING
+    pub read_page_size: Option<usize>,           // BROADCAST_READ_PAGE_SIZE
+    pub heartbeat_interval: Duration,            // HEARTBEAT_INTERVAL_MS
+    pub heartbeat_max_missed: u32,               // HEARTBEAT_MAX_MISSED
+    pub max_pending_callbacks: Option<usize>,    // MAX_PENDING_CALLBACKS
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        BroadcastConfig {
+            gossip_interval: Duration::from_millis(GOSSIP_INTERVAL_MS),
+            gossip_fanout: None,
+            gossip_limiter: None,
+            gossip_pacing: None,
+            compact: false,
+            topology_strategy: TopologyStrategy::UseProvided,
+            read_mode: ReadMode::Local,
+            ordering: Ordering::Unordered,
+            read_page_size: None,
+            heartbeat_interval: Duration::from_millis(failure::HEARTBEAT_INTERVAL_MS),
+            heartbeat_max_missed: failure::MAX_MISSED,
+            max_pending_callbacks: None,
+        }
+    }
+}
+
+impl BroadcastConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let gossip_interval = Duration::from_millis(
+            std::env::var("GOSSIP_INTERVAL_MS")
+                .ok()
+                .and_then(|ms| ms.parse().ok())
+                .unwrap_or(GOSSIP_INTERVAL_MS),
+        );
+        let gossip_fanout = match std::env::var("GOSSIP_FANOUT") {
+            Ok(fanout) => match fanout.parse() {
+                Ok(0) | Err(_) => bail!("GOSSIP_FANOUT must be a positive number, got {}", fanout),
+                Ok(fanout) => Some(fanout),
+            },
+            Err(_) => None,
+        };
+        let read_page_size = match std::env::var("BROADCAST_READ_PAGE_SIZE") {
+            Ok(size) => match size.parse() {
+                Ok(0) | Err(_) => bail!(
+                    "BROADCAST_READ_PAGE_SIZE must be a positive number, got {}",
+                    size
+                ),
+                Ok(size) => Some(size),
+            },
+            Err(_) => None,
+        };
+        Ok(BroadcastConfig {
+            gossip_interval,
+            gossip_fanout,
+            gossip_limiter: TokenBucket::from_env("GOSSIP_RATE_LIMIT", "GOSSIP_BURST")?,
+            gossip_pacing: AdaptiveInterval::from_env(gossip_interval)?,
+            compact: std::env::var_os("BROADCAST_COMPACT").is_some(),
+            topology_strategy: match std::env::var("BROADCAST_TOPOLOGY") {
+                Ok(strategy) => strategy.parse()?,
+                Err(_) => TopologyStrategy::UseProvided,
+            },
+            read_mode: match std::env::var("BROADCAST_READ_MODE") {
+                Ok(mode) => mode.parse()?,
+                Err(_) => ReadMode::Local,
+            },
+            ordering: match std::env::var("BROADCAST_ORDERING") {
+                Ok(ordering) => ordering.parse()?,
+                Err(_) => Ordering::Unordered,
+            },
+            read_page_size,
+            heartbeat_interval: failure::heartbeat_interval()?,
+            heartbeat_max_missed: failure::max_missed()?,
+            max_pending_callbacks: max_pending_callbacks()?,
+        })
+    }
+}
+
+/*
+Node in distributed system that handles broadcasting.
+All state is owned by the event loop and only touched from step, so merging a batch and answering a read can't
+interleave: a read always sees every batch that was applied before it, and no lock is needed. tests/broadcast.rs
+checks this against a running node whose reader, ticker and event loop all run at once.
+*/
+pub struct BroadcastNode<V: BroadcastValue = i64> {
+    net: Network,
+    messages: HashSet<V>, // Set so re-delivered broadcasts don't duplicate values in read_ok
+    watermark: Option<i64>, // With compaction on, every value in 0..watermark is held but no longer stored in messages
+    topology_strategy: TopologyStrategy,
+    read_mode: ReadMode,
+    ordering: Ordering,
+    causal: CausalBuffer<V>,
+    fifo: FifoBuffer<V>,
+    neighbors: Vec<NodeId>,
+    known: HashMap<NodeId, HashSet<V>>, // Values each neighbor has sent us or acked, so ticks only resend what's missing
+    callbacks: Callbacks<BroadcastNode<V>, BroadcastPayload<V>>,
+    backoff: HashMap<NodeId, Backoff>, // Only neighbors with unacked gossip have an entry
+    gossip_fanout: Option<usize>, // At most this many neighbors are gossiped to per tick; None gossips to all of them
+    passed_over: HashMap<NodeId, u64>, // Ticks in a row each neighbor had values pending but wasn't picked
+    detector: FailureDetector,
+    // Caps gossip batches per second (GOSSIP_RATE_LIMIT); replies to clients and acks don't take from it
+    gossip_limiter: Option<TokenBucket>,
+    // Tightens the gossip interval while values are outstanding and relaxes it once converged (GOSSIP_ADAPTIVE)
+    gossip_pacing: Option<AdaptiveInterval>,
+    gossip_sent: usize,
+    read_page_size: Option<usize>, // Paged reads are only answered in pages when this is set
+}
+
+impl<V: BroadcastValue> BroadcastNode<V> {
+    // How many nodes are known to hold a value: us, plus every neighbor that has sent it to us or acked it.
+    // Only neighbors are counted, so with a sparse topology a value can be everywhere without reaching a quorum here.
+    fn ack_count(&self, message: &V) -> usize {
+        1 + self
+            .known
+            .values()
+            .filter(|known| known.contains(message))
+            .count()
+    }
+
+    fn readable(&self, message: &V) -> bool {
+        match self.read_mode {
+            ReadMode::Local => true,
+            // Suspected peers can't ack anything, so a majority is only taken over the nodes still answering
+            ReadMode::Quorum => {
+                let live = self.net.cluster_size() - self.detector.suspected_count();
+                self.ack_count(message) > live / 2
+            }
+        }
+    }
+
+    fn read(&self) -> Vec<V> {
+        if self.ordering == Ordering::Fifo {
+            return self
+                .fifo
+                .delivered()
+                .filter(|message| self.readable(message))
+                .cloned()
+                .collect();
+        }
+        // Values were only compacted once they were readable, so the watermark expands back into all of them
+        let compacted = (0..self.watermark.unwrap_or(0)).filter_map(V::from_sequence);
+        compacted
+            .chain(
+                self.messages
+                    .iter()
+                    .filter(|message| self.readable(message))
+                    .cloned(),
+            )
+            .collect()
+    }
+
+    /*
+    One page of what read() returns, sorted so every page of a paged read cuts the same sequence: up to `page_size`
+    values from index `from` on, and where the next page starts if there's more. Values that arrive between pages
+    shift the indexes, so a page can repeat or skip a value near its edge; reading again from 0 catches up.
+    */
+    fn read_page(&self, from: usize, page_size: usize) -> BroadcastPayload<V> {
+        let mut messages = self.read();
+        messages.sort();
+        let end = from.saturating_add(page_size).min(messages.len());
+        let next = (end < messages.len()).then_some(end);
+        messages.truncate(end);
+        messages.drain(..from.min(end));
+        BroadcastPayload::ReadOk { messages, next }
+    }
+
+    fn compacted(&self, message: &V) -> bool {
+        message
+            .sequence()
+            .is_some_and(|sequence| (0..self.watermark.unwrap_or(0)).contains(&sequence))
+    }
+
+    // Records that `node` holds these values; compacted ones are already known to every neighbor
+    fn mark_known(&mut self, node: NodeId, messages: impl IntoIterator<Item = V>) {
+        let messages: Vec<V> = messages
+            .into_iter()
+            .filter(|message| !self.compacted(message))
+            .collect();
+        self.known.entry(node).or_default().extend(messages);
+    }
+
+    /*
+    Raises the watermark past every value right above it that is readable and acked by every neighbor, dropping those
+    values from messages and known. Nothing will ever be resent for them again, so it only pays off when values are
+    broadcast in increasing order from 0, as Maelstrom's broadcast workload does; any other value just stays in
+    messages as usual.
+    */
+    fn compact(&mut self) {
+        let Some(mut watermark) = self.watermark else {
+            return;
+        };
+        // Neighbors set later wouldn't be sent what's already compacted
+        if self.neighbors.is_empty() {
+            return;
+        }
+        while let Some(value) = V::from_sequence(watermark) {
+            let everywhere = self.messages.contains(&value)
+                && self.readable(&value)
+                && self.neighbors.iter().all(|neighbor| {
+                    self.known
+                        .get(neighbor)
+                        .is_some_and(|known| known.contains(&value))
+                });
+            if !everywhere {
+                break;
+            }
+            self.messages.remove(&value);
+            for known in self.known.values_mut() {
+                known.remove(&value);
+            }
+            watermark += 1;
+        }
+        self.watermark = Some(watermark);
+    }
+
+    fn send_batch(
+        &mut self,
+        dest: NodeId,
+        messages: Vec<V>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let neighbor = dest.clone();
+        let payload = BroadcastPayload::BroadcastBatch {
+            messages: messages.clone(),
+        };
+        self.net.send_with_callback(
+            &mut self.callbacks,
+            dest,
+            payload,
+            ACK_TIMEOUT,
+            output,
+            // Once the neighbor acks, stop resending those values to it
+            Box::new(move |node: &mut BroadcastNode<V>, reply, _output| {
+                if reply.is_err() {
+                    // Timed out; the values stay unacked, so gossip keeps retrying them on its own schedule
+                    return Ok(());
+                }
+                node.backoff.remove(&neighbor);
+                node.mark_known(neighbor, messages);
+                Ok(())
+            }),
+        )?;
+        self.gossip_sent += 1;
+        Ok(())
+    }
+
+    // Whether the rate limit (if any) allows another gossip batch right now
+    fn take_gossip_token(&mut self) -> bool {
+        self.gossip_limiter
+            .as_mut()
+            .is_none_or(|limiter| limiter.try_take())
+    }
+
+    // Whether this tick should retransmit to `neighbor`, counting down its backoff if not
+    fn due(&mut self, neighbor: &NodeId) -> bool {
+        match self.backoff.get_mut(neighbor) {
+            Some(backoff) if backoff.skip_ticks > 0 => {
+                backoff.skip_ticks -= 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    // Records a retransmit to `neighbor` that hasn't been acked (yet), growing its backoff
+    fn back_off(&mut self, neighbor: &NodeId) {
+        let jitter = self.net.next_random();
+        let backoff = self.backoff.entry(neighbor.clone()).or_default();
+        let ceiling = (1u32 << backoff.failures.min(31)).min(MAX_BACKOFF_TICKS);
+        // Equal jitter: wait at least half the ceiling, plus a random share of the other half
+        backoff.skip_ticks = ceiling / 2 + (jitter % (ceiling as u64 / 2 + 1)) as u32;
+        backoff.failures += 1;
+    }
+
+    /*
+    Picks min(gossip_fanout, pending) of the neighbors that have values pending, without replacement. Each draw is
+    weighted by how many values the neighbor is missing times how many ticks in a row it has been passed over, so the
+    biggest diffs go first but a neighbor that keeps losing the draw gets ever more likely to win it. A neighbor stays
+    pending until it acks, so even with a fan-out of 1 every neighbor is eventually sent everything.
+    */
+    fn sample_fanout(&mut self, mut pending: Vec<(NodeId, Vec<V>)>) -> Vec<(NodeId, Vec<V>)> {
+        let fanout = self.gossip_fanout.unwrap_or(pending.len());
+        let mut picked = Vec::new();
+        while picked.len() < fanout && !pending.is_empty() {
+            let weights: Vec<u64> = pending
+                .iter()
+                .map(|(neighbor, missing)| {
+                    let waited = self.passed_over.get(neighbor).copied().unwrap_or(0);
+                    missing.len() as u64 * (waited + 1)
+                })
+                .collect();
+            let mut draw = self.net.next_random() % weights.iter().sum::<u64>();
+            let index = weights
+                .iter()
+                .position(|weight| match draw.checked_sub(*weight) {
+                    Some(rest) => {
+                        draw = rest;
+                        false
+                    }
+                    None => true,
+                })
+                .unwrap_or(0);
+            picked.push(pending.swap_remove(index));
+        }
+        for (neighbor, _) in &pending {
+            *self.passed_over.entry(neighbor.clone()).or_default() += 1;
+        }
+        for (neighbor, _) in &picked {
+            self.passed_over.remove(neighbor);
+        }
+        picked
+    }
+
+    // Adds values to our set in one go, returning the ones we hadn't seen yet
+    fn merge(&mut self, messages: &[V]) -> Vec<V> {
+        messages
+            .iter()
+            .filter(|message| !self.compacted(message) && self.messages.insert((*message).clone()))
+            .cloned()
+            .collect()
+    }
+
+    // Forwards newly-seen values to every neighbor except the one we heard them from.
+    // Only new values are gossiped, otherwise nodes would forward each other forever.
+    fn gossip_new(
+        &mut self,
+        new: Vec<V>,
+        sender: &NodeId,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        if new.is_empty() {
+            return Ok(());
+        }
+        let targets: Vec<NodeId> = self
+            .neighbors
+            .iter()
+            .filter(|n| *n != sender)
+            .cloned()
+            .collect();
+        for neighbor in targets {
+            // Out of gossip budget; the values stay unacked, so anti-entropy sends them once there's budget again
+            if !self.take_gossip_token() {
+                break;
+            }
+            self.send_batch(neighbor, new.clone(), output)?;
+        }
+        Ok(())
+    }
+
+    // Sends an ordered (causal or fifo) copy of `message` to `peer`, resending it until the peer acks
+    fn send_until_acked(
+        &mut self,
+        peer: NodeId,
+        message: V,
+        payload: BroadcastPayload<V>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        self.net.send_with_callback(
+            &mut self.callbacks,
+            peer.clone(),
+            payload.clone(),
+            ACK_TIMEOUT,
+            output,
+            // The peer drops copies it has already delivered, so resending after a lost ack is harmless
+            Box::new(
+                move |node: &mut BroadcastNode<V>, reply, mut output| match reply {
+                    Ok(_) => {
+                        node.mark_known(peer, [message]);
+                        Ok(())
+                    }
+                    Err(_) => node.send_until_acked(peer, message, payload, &mut output),
+                },
+            ),
+        )
+    }
+
+    fn handle_message(
+        &mut self,
+        input: Message<BroadcastPayload<V>>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        if let Some(callback) = self.callbacks.take(&input) {
+            return callback(self, Ok(input), output);
+        }
+
+        match &input.body.payload {
+            BroadcastPayload::Broadcast { message } if self.ordering == Ordering::Causal => {
+                let clock = self.causal.stamp(self.net.node_id.as_str());
+                self.merge(std::slice::from_ref(message));
+                self.net
+                    .reply(&input, BroadcastPayload::BroadcastOk {}, output)?;
+                let payload = BroadcastPayload::CausalBroadcast {
+                    message: message.clone(),
+                    clock,
+                };
+                let peers: Vec<NodeId> = self.net.peers().cloned().collect();
+                for peer in peers {
+                    self.send_until_acked(peer, message.clone(), payload.clone(), output)?;
+                }
+            }
+            BroadcastPayload::Broadcast { message } if self.ordering == Ordering::Fifo => {
+                let origin = self.net.node_id.clone();
+                let seq = self.fifo.stamp(origin.as_str(), message.clone());
+                self.merge(std::slice::from_ref(message));
+                self.net
+                    .reply(&input, BroadcastPayload::BroadcastOk {}, output)?;
+                let payload = BroadcastPayload::FifoBroadcast {
+                    message: message.clone(),
+                    origin,
+                    seq,
+                };
+                let peers: Vec<NodeId> = self.net.peers().cloned().collect();
+                for peer in peers {
+                    self.send_until_acked(peer, message.clone(), payload.clone(), output)?;
+                }
+            }
+            BroadcastPayload::FifoBroadcast {
+                message,
+                origin,
+                seq,
+            } => {
+                self.mark_known(input.src.clone(), [message.clone()]);
+                let delivered = self.fifo.receive(origin.as_str(), *seq, message.clone());
+                self.merge(&delivered);
+                self.net
+                    .reply(&input, BroadcastPayload::FifoBroadcastOk {}, output)?;
+            }
+            BroadcastPayload::CausalBroadcast { message, clock } => {
+                self.mark_known(input.src.clone(), [message.clone()]);
+                let delivered =
+                    self.causal
+                        .receive(input.src.as_str(), clock.clone(), message.clone());
+                self.merge(&delivered);
+                self.net
+                    .reply(&input, BroadcastPayload::CausalBroadcastOk {}, output)?;
+            }
+            BroadcastPayload::Broadcast { message } => {
+                // Ack the sender before fanning out so broadcast_ok isn't delayed by gossip
+                let new = self.merge(std::slice::from_ref(message));
+                self.net
+                    .reply(&input, BroadcastPayload::BroadcastOk {}, output)?;
+                self.gossip_new(new, &input.src, output)?;
+            }
+            BroadcastPayload::BroadcastBatch { messages } => {
+                // The whole batch is in our set before it's acked, so no read after the ack can miss part of it
+                let new = self.merge(messages);
+                self.net
+                    .reply(&input, BroadcastPayload::BroadcastBatchOk {}, output)?;
+                // The neighbor can reach us again (e.g. a partition healed), so retry it on the very next tick
+                self.backoff.remove(&input.src);
+                self.mark_known(input.src.clone(), messages.iter().cloned());
+                self.gossip_new(new, &input.src, output)?;
+            }
+            BroadcastPayload::BroadcastOk { .. }
+            | BroadcastPayload::BroadcastBatchOk { .. }
+            | BroadcastPayload::CausalBroadcastOk { .. }
+            | BroadcastPayload::FifoBroadcastOk { .. } => {
+                self.net.reject_unsupported(&input, output)?;
+            }
+            BroadcastPayload::Read { from } => {
+                let payload = match (from, self.read_page_size) {
+                    (Some(from), Some(page_size)) => self.read_page(*from, page_size),
+                    _ => BroadcastPayload::ReadOk {
+                        messages: self.read(),
+                        next: None,
+                    },
+                };
+                self.net.reply(&input, payload, output)?;
+            }
+            BroadcastPayload::ReadOk { .. } => {
+                self.net.reject_unsupported(&input, output)?;
+            }
+            BroadcastPayload::Topology { topology } => {
+                self.neighbors = self.topology_strategy.neighbors(&self.net, topology);
+                self.net
+                    .reply(&input, BroadcastPayload::TopologyOk {}, output)?;
+            }
+            BroadcastPayload::TopologyOk { .. } => {
+                self.net.reject_unsupported(&input, output)?;
+            }
+            BroadcastPayload::Ping {} => {
+                self.net.reply(&input, BroadcastPayload::Pong {}, output)?;
+            }
+            BroadcastPayload::Pong {} => {
+                if self.detector.pong(&input.src) {
+                    eprintln!("{} is answering again", input.src);
+                }
+            }
+            #[cfg(feature = "debug")]
+            BroadcastPayload::Debug {} => {
+                let mut snapshot = self.net.debug_snapshot(self.callbacks.len());
+                snapshot.set_size = Some(self.read().len());
+                self.net
+                    .reply(&input, BroadcastPayload::DebugOk { snapshot }, output)?;
+            }
+            #[cfg(feature = "debug")]
+            BroadcastPayload::DebugOk { .. } => {
+                self.net.reject_unsupported(&input, output)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn heartbeat(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let (peers, suspected) = self.detector.heartbeat();
+        for peer in suspected {
+            eprintln!("Suspecting {}: it stopped answering pings", peer);
+        }
+        for peer in peers {
+            self.net
+                .send(peer, BroadcastPayload::<V>::Ping {}, output)?;
+        }
+        Ok(())
+    }
+
+    // A gossip tick; `forced` (GossipNow) sends to every neighbor with values pending, whatever backoff, fan-out and
+    // rate limit would say
+    fn gossip(&mut self, forced: bool, output: &mut impl Write) -> anyhow::Result<()> {
+        for (callback, timeout) in self.callbacks.sweep_expired() {
+            callback(self, Err(timeout.into()), output)?;
+        }
+        self.compact();
+        if self.ordering != Ordering::Unordered {
+            return Ok(());
+        }
+
+        // Anti-entropy: resend known_values - acked[neighbor] as one batch per neighbor, so values lost to
+        // partitions still arrive without costing a message per value
+        let mut pending = Vec::new();
+        // Values missing across all neighbors, backed-off ones included, which is what paces adaptive gossip
+        let mut outstanding = 0;
+        for neighbor in self.neighbors.clone() {
+            let due = forced || self.due(&neighbor);
+            if !due && self.gossip_pacing.is_none() {
+                continue;
+            }
+            let known = self.known.get(&neighbor);
+            let mut missing: Vec<V> = self
+                .messages
+                .iter()
+                .filter(|message| !known.is_some_and(|known| known.contains(message)))
+                .cloned()
+                .collect();
+            outstanding += missing.len();
+            missing.sort();
+            if due && !missing.is_empty() {
+                pending.push((neighbor, missing));
+            }
+        }
+        if let Some(pacing) = self.gossip_pacing.as_ref().filter(|_| !forced) {
+            pacing.adjust(outstanding);
+        }
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let pending = if forced {
+            pending
+        } else {
+            self.sample_fanout(pending)
+        };
+        let sent_before = self.gossip_sent;
+        for (neighbor, missing) in pending {
+            // Whoever's left over is deferred to a later tick, without counting as an unacked round
+            if !forced && !self.take_gossip_token() {
+                break;
+            }
+            // Assume this round goes unacked; the ack's callback clears the backoff if it doesn't
+            self.back_off(&neighbor);
+            self.send_batch(neighbor, missing, output)?;
+        }
+        if log_enabled() {
+            eprintln!(
+                "gossip tick sent {} messages ({} total)",
+                self.gossip_sent - sent_before,
+                self.gossip_sent
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<V: BroadcastValue> BroadcastNode<V> {
+    // Builds the node from_init would, but set up by `config` instead of the environment
+    pub fn with_config(
+        net: Network,
+        inject: mpsc::Sender<Event<BroadcastPayload<V>, InjectedPayload>>,
+        config: BroadcastConfig,
+    ) -> Self {
+        let peer_count_hint = net.peer_count_hint();
+        let detector = FailureDetector::new(net.peers(), config.heartbeat_max_missed);
+        let node = BroadcastNode {
+            net,
+            messages: HashSet::new(),
+            watermark: config.compact.then_some(0),
+            topology_strategy: config.topology_strategy,
+            read_mode: config.read_mode,
+            ordering: config.ordering,
+            causal: CausalBuffer::default(),
+            fifo: FifoBuffer::default(),
+            neighbors: Vec::new(),
+            known: HashMap::with_capacity(peer_count_hint),
+            callbacks: Callbacks::with_optional_limit(config.max_pending_callbacks),
+            backoff: HashMap::with_capacity(peer_count_hint),
+            gossip_fanout: config.gossip_fanout,
+            passed_over: HashMap::with_capacity(peer_count_hint),
+            detector,
+            gossip_limiter: config.gossip_limiter,
+            gossip_pacing: config.gossip_pacing.clone(),
+            gossip_sent: 0,
+            read_page_size: config.read_page_size,
+        };
+        spawn_ticker(
+            inject.clone(),
+            config.heartbeat_interval,
+            InjectedPayload::Heartbeat,
+        );
+        match config.gossip_pacing {
+            Some(pacing) => {
+                spawn_ticker_with(inject, move || pacing.current(), InjectedPayload::Gossip)
+            }
+            None => spawn_ticker(inject, config.gossip_interval, InjectedPayload::Gossip),
+        }
+        node
+    }
+}
+
+impl<V: BroadcastValue> Node<BroadcastPayload<V>, InjectedPayload> for BroadcastNode<V> {
+    fn from_init(
+        net: Network,
+        inject: mpsc::Sender<Event<BroadcastPayload<V>, InjectedPayload>>,
+    ) -> anyhow::Result<Self> {
+        Ok(BroadcastNode::with_config(
+            net,
+            inject,
+            BroadcastConfig::from_env()?,
+        ))
+    }
+
+    fn step(
+        &mut self,
+        event: Event<BroadcastPayload<V>, InjectedPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::Message(input) => self.handle_message(input, output),
+            Event::Injected(InjectedPayload::Gossip) => self.gossip(false, output),
+            Event::Injected(InjectedPayload::GossipNow) => self.gossip(true, output),
+            Event::Injected(InjectedPayload::Heartbeat) => self.heartbeat(output),
+            Event::Eof => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::rng::XorShift;
+    use crate::simulation::Cluster;
+
+    use anyhow::Context;
+    use std::collections::VecDeque;
+
+    type TestCluster<V = i64> = Cluster<BroadcastNode<V>, BroadcastPayload<V>, InjectedPayload>;
+
+    // A cluster of `nodes` broadcast nodes, each set up by `config` rather than from the environment
+    fn cluster<V: BroadcastValue>(
+        nodes: usize,
+        config: impl Fn() -> BroadcastConfig,
+    ) -> anyhow::Result<TestCluster<V>> {
+        Cluster::with_nodes(nodes, |net, inject| {
+            Ok(BroadcastNode::with_config(net, inject, config()))
+        })
+    }
+
+    // Hands every node its topology; `neighbors` maps a node's index to its neighbors' indices
+    fn set_topology<V: BroadcastValue>(
+        cluster: &mut TestCluster<V>,
+        neighbors: impl Fn(usize) -> Vec<usize>,
+    ) -> anyhow::Result<()> {
+        let ids = cluster.node_ids().to_vec();
+        let topology: HashMap<NodeId, Vec<NodeId>> = (0..ids.len())
+            .map(|i| {
+                let listed = neighbors(i).into_iter().map(|j| ids[j].clone()).collect();
+                (ids[i].clone(), listed)
+            })
+            .collect();
+        for id in &ids {
+            let topology = topology.clone();
+            cluster.request(id, BroadcastPayload::Topology { topology })?;
+        }
+        Ok(())
+    }
+
+    fn ring(nodes: usize) -> impl Fn(usize) -> Vec<usize> {
+        move |i| vec![(i + nodes - 1) % nodes, (i + 1) % nodes]
+    }
+
+    fn line(nodes: usize) -> impl Fn(usize) -> Vec<usize> {
+        move |i| {
+            [i.checked_sub(1), (i + 1 < nodes).then_some(i + 1)]
+                .into_iter()
+                .flatten()
+                .collect()
+        }
+    }
+
+    /* How one run of the partition scenario ended */
+    struct ScenarioResult {
+        reads: Vec<Vec<i64>>, // Each node's final read, sorted, in node order
+        stored: usize,        // Values held in `messages` across the cluster
+    }
+
+    /*
+    Five nodes on a ring take broadcasts at random nodes, before and while the ring is cut in two, then the cut heals
+    and every node has to end up reading every value within a bounded number of gossip rounds.
+    */
+    fn run_scenario(compact: bool) -> anyhow::Result<ScenarioResult> {
+        const NODES: usize = 5;
+        // Enough for every backoff to run out at least twice after the partition heals
+        const MAX_ROUNDS: usize = 4 * MAX_BACKOFF_TICKS as usize;
+
+        let mut cluster = cluster(NODES, || BroadcastConfig {
+            compact,
+            ..BroadcastConfig::default()
+        })?;
+        let ids = cluster.node_ids().to_vec();
+        set_topology(&mut cluster, ring(NODES))?;
+
+        // Fixed seed, so every run broadcasts to the same nodes in the same order
+        let mut rng = 0x2545_F491_4F6C_DD1D_u64;
+        let mut sent = Vec::new();
+        let mut broadcast = |cluster: &mut TestCluster, count: usize| -> anyhow::Result<()> {
+            for _ in 0..count {
+                rng ^= rng << 13;
+                rng ^= rng >> 7;
+                rng ^= rng << 17;
+                let message = sent.len() as i64;
+                sent.push(message);
+                let node = &ids[rng as usize % NODES];
+                cluster.request(node, BroadcastPayload::Broadcast { message })?;
+            }
+            Ok(())
+        };
+
+        broadcast(&mut cluster, 10)?;
+        cluster.deliver_all()?;
+        cluster.partition(&[&["n0", "n1"], &["n2", "n3", "n4"]]);
+        for _ in 0..5 {
+            broadcast(&mut cluster, 4)?;
+            cluster.tick(InjectedPayload::Gossip)?;
+            cluster.deliver_all()?;
+        }
+
+        let expected: HashSet<i64> = sent.into_iter().collect();
+        if lagging_nodes(&mut cluster, &expected)?.is_empty() {
+            bail!("scenario is broken: every value crossed the partition");
+        }
+
+        cluster.heal();
+        let mut rounds = 0;
+        loop {
+            rounds += 1;
+            cluster.tick(InjectedPayload::Gossip)?;
+            cluster.deliver_all()?;
+            let lagging = lagging_nodes(&mut cluster, &expected)?;
+            if lagging.is_empty() {
+                break;
+            }
+            if rounds == MAX_ROUNDS {
+                bail!("after {} rounds: {}", rounds, lagging.join(", "));
+            }
+        }
+        // A couple more rounds so every ack is in and compaction (if it's on) has caught up
+        for _ in 0..2 {
+            cluster.tick(InjectedPayload::Gossip)?;
+            cluster.deliver_all()?;
+        }
+
+        let mut reads = Vec::new();
+        let mut stored = 0;
+        for id in &ids {
+            let mut read = read_node(&mut cluster, id)?;
+            read.sort_unstable();
+            reads.push(read);
+            stored += cluster.node(id).map_or(0, |node| node.messages.len());
+        }
+        Ok(ScenarioResult { reads, stored })
+    }
+
+    #[test]
+    fn ring_converges_after_partition_heals() -> anyhow::Result<()> {
+        run_scenario(false).map(|_| ())
+    }
+
+    // With BROADCAST_COMPACT every node has to read exactly what it read without compaction, while storing fewer values
+    #[test]
+    fn compaction_reads_the_same_from_fewer_values() -> anyhow::Result<()> {
+        let plain = run_scenario(false)?;
+        let compacted = run_scenario(true)?;
+        assert_eq!(compacted.reads, plain.reads);
+        assert!(
+            compacted.stored < plain.stored,
+            "compaction stored {} values, {} without it",
+            compacted.stored,
+            plain.stored
+        );
+        Ok(())
+    }
+
+    /*
+    n0 is the hub of a star, so every leaf can only get values from n0's gossip ticks: each value is broadcast to n0
+    and its immediate forwards are dropped. A new value arrives every round for a while, so leaves that have caught up
+    keep competing with ones that haven't, and with a fan-out of 1 each tick may only send one batch. Every leaf still
+    has to end up with every value. Every node's rng is seeded from `seed`; returns every message a gossip tick sent,
+    in order.
+    */
+    fn run_fanout_scenario(seed: u64) -> anyhow::Result<Vec<String>> {
+        const NODES: usize = 5;
+        const BROADCAST_ROUNDS: usize = 20;
+        const MAX_ROUNDS: usize = 200;
+
+        let mut cluster: TestCluster = Cluster::with_nodes(NODES, |net, inject| {
+            let rng = XorShift::new(seed + net.node_index() as u64);
+            let config = BroadcastConfig {
+                gossip_fanout: Some(1),
+                ..BroadcastConfig::default()
+            };
+            Ok(BroadcastNode::with_config(
+                net.with_rng(rng),
+                inject,
+                config,
+            ))
+        })?;
+        let hub = cluster.node_ids()[0].clone();
+        set_topology(&mut cluster, |i| match i {
+            0 => (1..NODES).collect(),
+            _ => vec![0],
+        })?;
+
+        let mut expected = HashSet::new();
+        let mut sent = Vec::new();
+        let mut rounds = 0;
+        loop {
+            if rounds < BROADCAST_ROUNDS {
+                let message = rounds as i64;
+                expected.insert(message);
+                cluster.request(&hub, BroadcastPayload::Broadcast { message })?;
+                while cluster.drop_next().is_some() {}
+            }
+            rounds += 1;
+            cluster.tick(InjectedPayload::Gossip)?;
+            sent.extend(
+                cluster
+                    .in_flight_messages()
+                    .map(|message| format!("{:?}", message)),
+            );
+            assert!(
+                cluster.in_flight() <= 1,
+                "a tick sent {} batches with a fan-out of 1",
+                cluster.in_flight()
+            );
+            cluster.deliver_all()?;
+            if rounds >= BROADCAST_ROUNDS && lagging_nodes(&mut cluster, &expected)?.is_empty() {
+                break;
+            }
+            if rounds == MAX_ROUNDS {
+                bail!(
+                    "after {} rounds: {}",
+                    rounds,
+                    lagging_nodes(&mut cluster, &expected)?.join(", ")
+                );
+            }
+        }
+        Ok(sent)
+    }
+
+    #[test]
+    fn fanout_of_one_reaches_every_leaf() -> anyhow::Result<()> {
+        run_fanout_scenario(7).map(|_| ())
+    }
+
+    // Every random pick (which leaf, how long to back off) comes from the seeded rng, so a rerun has to match exactly
+    #[test]
+    fn same_seed_gossips_the_same_messages() -> anyhow::Result<()> {
+        let first = run_fanout_scenario(7)?;
+        let second = run_fanout_scenario(7)?;
+        if let Some(i) =
+            (0..first.len().max(second.len())).find(|i| first.get(*i) != second.get(*i))
+        {
+            panic!(
+                "rerunning with the same seed diverged at message {}:\n  first:  {:?}\n  second: {:?}",
+                i,
+                first.get(i),
+                second.get(i)
+            );
+        }
+        Ok(())
+    }
+
+    /*
+    Three nodes ping each other every heartbeat. Nobody may be suspected while all are connected; once n2 is cut off,
+    n0 has to suspect it after max_missed heartbeats (and not before), and once the cut heals a single heartbeat has to
+    reinstate it.
+    */
+    #[test]
+    fn cut_off_node_is_suspected_then_reinstated() -> anyhow::Result<()> {
+        const MAX_MISSED: usize = 3;
+        let mut cluster: TestCluster = cluster(3, || BroadcastConfig {
+            heartbeat_max_missed: MAX_MISSED as u32,
+            ..BroadcastConfig::default()
+        })?;
+        let (n0, n2) = (NodeId::from("n0"), NodeId::from("n2"));
+        let suspects = |cluster: &TestCluster| {
+            cluster
+                .node(&n0)
+                .is_some_and(|node| node.detector.is_suspected(&n2))
+        };
+        let heartbeat = |cluster: &mut TestCluster| -> anyhow::Result<()> {
+            cluster.tick(InjectedPayload::Heartbeat)?;
+            cluster.deliver_all()
+        };
+
+        for _ in 0..2 * MAX_MISSED {
+            heartbeat(&mut cluster)?;
+            assert!(
+                !suspects(&cluster),
+                "n2 was suspected while it was answering"
+            );
+        }
+
+        cluster.partition(&[&["n0", "n1"]]);
+        let mut suspected_after = 0;
+        while !suspects(&cluster) {
+            suspected_after += 1;
+            assert!(
+                suspected_after <= MAX_MISSED + 1,
+                "n2 wasn't suspected after {} missed heartbeats",
+                suspected_after - 1
+            );
+            heartbeat(&mut cluster)?;
+        }
+        // The first heartbeat after the cut only sends the ping that goes unanswered
+        assert_eq!(suspected_after, MAX_MISSED + 1);
+
+        cluster.heal();
+        heartbeat(&mut cluster)?;
+        assert!(
+            !suspects(&cluster),
+            "n2 is still suspected after answering again"
+        );
+        Ok(())
+    }
+
+    /*
+    Five nodes in a line. n0 takes values while cut off, and its gossip keeps failing until its backoff has it
+    skipping ticks. Once the cut heals, a single GossipNow followed by delivering everything has to get every value to
+    every node, with no timer involved.
+    */
+    #[test]
+    fn gossip_now_flushes_backed_off_values() -> anyhow::Result<()> {
+        const NODES: usize = 5;
+        const CUT_TICKS: usize = 6;
+        let mut cluster = cluster(NODES, BroadcastConfig::default)?;
+        let n0 = cluster.node_ids()[0].clone();
+        set_topology(&mut cluster, line(NODES))?;
+
+        cluster.partition(&[&["n0"]]);
+        let expected: HashSet<i64> = (0..5).collect();
+        for message in expected.iter().copied() {
+            cluster.request(&n0, BroadcastPayload::Broadcast { message })?;
+        }
+        for _ in 0..CUT_TICKS {
+            cluster.deliver_all()?;
+            cluster.tick(InjectedPayload::Gossip)?;
+        }
+        cluster.deliver_all()?;
+
+        cluster.heal();
+        cluster.tick(InjectedPayload::GossipNow)?;
+        cluster.deliver_all()?;
+        let lagging = lagging_nodes(&mut cluster, &expected)?;
+        assert!(
+            lagging.is_empty(),
+            "after one GossipNow {}",
+            lagging.join(", ")
+        );
+        Ok(())
+    }
+
+    /*
+    The same machinery with String values: three fully connected nodes take broadcasts while n2 is cut off, and once
+    the cut heals, gossip batches and their acks have to carry the strings to it.
+    */
+    #[test]
+    fn string_values_converge() -> anyhow::Result<()> {
+        const MAX_ROUNDS: usize = 4 * MAX_BACKOFF_TICKS as usize;
+        let mut cluster: TestCluster<String> = cluster(3, BroadcastConfig::default)?;
+        let ids = cluster.node_ids().to_vec();
+        set_topology(&mut cluster, |i| (0..3).filter(|j| *j != i).collect())?;
+
+        cluster.partition(&[&["n0", "n1"]]);
+        let mut expected = HashSet::new();
+        for (i, word) in [
+            "alpha",
+            "beta",
+            "gamma",
+            "delta",
+            "epsilon",
+            "\"quoted\" {json}",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let message = word.to_string();
+            expected.insert(message.clone());
+            cluster.request(&ids[i % 2], BroadcastPayload::Broadcast { message })?;
+        }
+        cluster.deliver_all()?;
+        if lagging_nodes(&mut cluster, &expected)?.is_empty() {
+            bail!("scenario is broken: every value crossed the partition");
+        }
+
+        cluster.heal();
+        for rounds in 1.. {
+            cluster.tick(InjectedPayload::Gossip)?;
+            cluster.deliver_all()?;
+            let lagging = lagging_nodes(&mut cluster, &expected)?;
+            if lagging.is_empty() {
+                break;
+            }
+            if rounds == MAX_ROUNDS {
+                bail!("after {} rounds: {}", rounds, lagging.join(", "));
+            }
+        }
+        Ok(())
+    }
+
+    /*
+    In fifo mode, n1 gets n2's second value before its first (as if a retry of the first had lost the race) and
+    mustn't read it until the first arrives; then n0's own broadcasts have to reach n1 as well, listed ahead of n2's
+    (origins are read in sorted order, each one's values in the order it sent them).
+    */
+    #[test]
+    fn fifo_holds_values_back_until_their_predecessor() -> anyhow::Result<()> {
+        let mut cluster: TestCluster = cluster(3, || BroadcastConfig {
+            ordering: Ordering::Fifo,
+            ..BroadcastConfig::default()
+        })?;
+        let (n0, n1, n2) = (NodeId::from("n0"), NodeId::from("n1"), NodeId::from("n2"));
+        let from_n2 = |message, seq| BroadcastPayload::FifoBroadcast {
+            message,
+            origin: n2.clone(),
+            seq,
+        };
+
+        cluster.request(&n1, from_n2(20, 2))?;
+        assert_eq!(read_node(&mut cluster, &n1)?, Vec::<i64>::new());
+        cluster.request(&n1, from_n2(10, 1))?;
+        assert_eq!(read_node(&mut cluster, &n1)?, [10, 20]);
+        for message in [40, 41] {
+            cluster.request(&n0, BroadcastPayload::Broadcast { message })?;
+        }
+        cluster.deliver_all()?;
+        assert_eq!(read_node(&mut cluster, &n1)?, [40, 41, 10, 20]);
+        Ok(())
+    }
+
+    /*
+    In causal mode, n1 broadcasts 20 after it has seen n0's 10, and n2 gets n1's copy first (n0's is held up in the
+    network). n2 mustn't read 20 until 10 has arrived; then it reads both (reads aren't ordered in causal mode).
+    */
+    #[test]
+    fn causal_holds_values_back_until_their_dependencies() -> anyhow::Result<()> {
+        let mut cluster: TestCluster = cluster(3, || BroadcastConfig {
+            ordering: Ordering::Causal,
+            ..BroadcastConfig::default()
+        })?;
+        let (n0, n1, n2) = (NodeId::from("n0"), NodeId::from("n1"), NodeId::from("n2"));
+
+        cluster.request(&n0, BroadcastPayload::Broadcast { message: 10 })?;
+        cluster.deliver_next()?;
+        let held_up = cluster.drop_next().context("n0 sent nothing to n2")?;
+        assert_eq!(held_up.dest, n2);
+        cluster.deliver_all()?;
+        assert_eq!(read_node(&mut cluster, &n1)?, [10]);
+
+        cluster.request(&n1, BroadcastPayload::Broadcast { message: 20 })?;
+        cluster.deliver_all()?;
+        assert_eq!(read_node(&mut cluster, &n2)?, Vec::<i64>::new());
+        cluster.deliver(held_up)?;
+        cluster.deliver_all()?;
+        for node in [&n0, &n2] {
+            let mut read = read_node(&mut cluster, node)?;
+            read.sort();
+            assert_eq!(read, [10, 20], "{} read the wrong values", node);
+        }
+        Ok(())
+    }
+
+    /*
+    Five nodes on a ring, each limited to RATE gossip batches a second with bursts of BURST, on a clock the driver
+    moves by TICK_MS per gossip tick. 20 values a tick land on them for the first 10 ticks, far more than the budget
+    can gossip one batch per value. In every one-second window no node may send more than BURST + RATE batches, every
+    broadcast still has to be acked to its client at once, and every node still has to end up reading every value.
+    */
+    #[test]
+    fn rate_limited_gossip_stays_under_the_cap() -> anyhow::Result<()> {
+        const NODES: usize = 5;
+        const RATE: u32 = 20;
+        const BURST: u32 = 5;
+        const TICK_MS: u64 = 100;
+        const TICKS_PER_WINDOW: usize = 10;
+        const FLOOD_TICKS: usize = 10;
+        const PER_TICK: usize = 20;
+        const MAX_ROUNDS: usize = 200;
+
+        let clock = MockClock::new(0);
+        let mut cluster: TestCluster = cluster(NODES, || BroadcastConfig {
+            gossip_limiter: Some(TokenBucket::with_clock(BURST, RATE, clock.clone())),
+            ..BroadcastConfig::default()
+        })?;
+        let ids = cluster.node_ids().to_vec();
+        set_topology(&mut cluster, ring(NODES))?;
+        cluster.take_client_replies();
+
+        let gossip_sent = |cluster: &TestCluster| -> Vec<usize> {
+            ids.iter()
+                .map(|id| cluster.node(id).map_or(0, |node| node.gossip_sent))
+                .collect()
+        };
+        let cap = (BURST + RATE) as usize;
+        let mut expected = HashSet::new();
+        let mut window_start = gossip_sent(&cluster);
+        let mut rounds = 0;
+        loop {
+            if rounds < FLOOD_TICKS {
+                for i in 0..PER_TICK {
+                    let message = expected.len() as i64;
+                    expected.insert(message);
+                    cluster.request(&ids[i % NODES], BroadcastPayload::Broadcast { message })?;
+                }
+                let acked = cluster
+                    .take_client_replies()
+                    .iter()
+                    .filter(|reply| matches!(reply.body.payload, BroadcastPayload::BroadcastOk {}))
+                    .count();
+                assert_eq!(
+                    acked, PER_TICK,
+                    "not every broadcast was acked straight away"
+                );
+            }
+            cluster.deliver_all()?;
+            cluster.tick(InjectedPayload::Gossip)?;
+            cluster.deliver_all()?;
+            clock.advance(TICK_MS);
+            rounds += 1;
+
+            let converged =
+                rounds >= FLOOD_TICKS && lagging_nodes(&mut cluster, &expected)?.is_empty();
+            if rounds % TICKS_PER_WINDOW == 0 || converged {
+                let sent = gossip_sent(&cluster);
+                for (i, (before, after)) in window_start.iter().zip(&sent).enumerate() {
+                    assert!(
+                        after - before <= cap,
+                        "{} sent {} gossip batches in a second, over the cap of {}",
+                        ids[i],
+                        after - before,
+                        cap
+                    );
+                }
+                window_start = sent;
+            }
+            if converged {
+                return Ok(());
+            }
+            if rounds == MAX_ROUNDS {
+                bail!(
+                    "after {} ticks: {}",
+                    rounds,
+                    lagging_nodes(&mut cluster, &expected)?.join(", ")
+                );
+            }
+        }
+    }
+
+    /*
+    Three nodes in a line, gossip paced by AdaptiveInterval (300ms to start, 50ms..600ms in 50ms steps). n0 takes a
+    burst of values while cut off, so they stay outstanding tick after tick and its interval has to shrink to the
+    floor; once the cut heals and everyone has every value, quiet ticks have to bring it back up to the ceiling.
+    */
+    #[test]
+    fn adaptive_interval_tightens_then_relaxes() -> anyhow::Result<()> {
+        const BURST: i64 = 20;
+        const CUT_TICKS: usize = 6;
+        const MAX_ROUNDS: usize = 50;
+        let start = Duration::from_millis(300);
+        let floor = Duration::from_millis(50);
+        let ceiling = Duration::from_millis(600);
+        let step = Duration::from_millis(50);
+
+        let mut cluster: TestCluster = cluster(3, || BroadcastConfig {
+            gossip_pacing: Some(AdaptiveInterval::new(start, floor, ceiling, step)),
+            ..BroadcastConfig::default()
+        })?;
+        let n0 = cluster.node_ids()[0].clone();
+        set_topology(&mut cluster, line(3))?;
+        let interval = |cluster: &TestCluster| {
+            cluster
+                .node(&n0)
+                .and_then(|node| node.gossip_pacing.as_ref())
+                .map(AdaptiveInterval::current)
+        };
+
+        cluster.partition(&[&["n0"], &["n1", "n2"]]);
+        let expected: HashSet<i64> = (0..BURST).collect();
+        for message in 0..BURST {
+            cluster.request(&n0, BroadcastPayload::Broadcast { message })?;
+        }
+        for _ in 0..CUT_TICKS {
+            cluster.deliver_all()?;
+            cluster.tick(InjectedPayload::Gossip)?;
+        }
+        assert_eq!(interval(&cluster), Some(floor));
+
+        cluster.heal();
+        let mut rounds = 0;
+        while !lagging_nodes(&mut cluster, &expected)?.is_empty() {
+            if rounds == MAX_ROUNDS {
+                bail!("no convergence within {} rounds", MAX_ROUNDS);
+            }
+            cluster.deliver_all()?;
+            cluster.tick(InjectedPayload::Gossip)?;
+            cluster.deliver_all()?;
+            rounds += 1;
+        }
+        // Every step up needs a quiet tick; give it the whole climb from the floor
+        for _ in 0..(ceiling - floor).as_millis() / step.as_millis() {
+            cluster.tick(InjectedPayload::Gossip)?;
+            cluster.deliver_all()?;
+        }
+        assert_eq!(interval(&cluster), Some(ceiling));
+        Ok(())
+    }
+
+    /*
+    A node holding 10000 values with a read page size of 999 has to hand them out in sorted pages of at most 999, each
+    continuing where the last one's `next` said, until a page without `next`; together the pages have to be exactly
+    the set. A read without `from` still has to get the whole set in one reply.
+    */
+    #[test]
+    fn paged_reads_cover_the_whole_set() -> anyhow::Result<()> {
+        const VALUES: i64 = 10_000;
+        const PAGE_SIZE: usize = 999;
+        let mut node: BroadcastNode = test_harness::init("n0", &["n0"])?;
+        node.read_page_size = Some(PAGE_SIZE);
+        node.messages = (0..VALUES).map(|i| (i * 7919) % VALUES).collect();
+        let mut msg_id = 0;
+        let mut read =
+            |node: &mut BroadcastNode, from| -> anyhow::Result<(Vec<i64>, Option<usize>)> {
+                msg_id += 1;
+                let request = Event::Message(Message {
+                    src: NodeId::from("c1"),
+                    dest: NodeId::from("n0"),
+                    body: MessageBody {
+                        msg_id: Some(MsgId(msg_id)),
+                        in_reply_to: None,
+                        payload: BroadcastPayload::Read { from },
+                    },
+                });
+                match test_harness::drive(node, vec![request])?.pop() {
+                    Some(Message {
+                        body:
+                            MessageBody {
+                                payload: BroadcastPayload::ReadOk { messages, next },
+                                ..
+                            },
+                        ..
+                    }) => Ok((messages, next)),
+                    other => bail!("a read got {:?}", other),
+                }
+            };
+
+        let mut streamed = Vec::new();
+        let mut from = Some(0);
+        while let Some(start) = from {
+            let (page, next) = read(&mut node, Some(start))?;
+            assert!(
+                page.len() <= PAGE_SIZE && next.is_none_or(|next| next == start + page.len()),
+                "the page from {} had {} values and next {:?}",
+                start,
+                page.len(),
+                next
+            );
+            streamed.extend(page);
+            from = next;
+        }
+        let expected: Vec<i64> = (0..VALUES).collect();
+        assert!(
+            streamed == expected,
+            "the pages weren't 0..{} in order",
+            VALUES
+        );
+        let (whole, next) = read(&mut node, None)?;
+        assert_eq!((whole.len(), next), (expected.len(), None));
+        Ok(())
+    }
+
+    /*
+    A node told to expect 64 nodes (as --node-count-hint 64 does) has to start with room for 63 peers in its ack,
+    backoff and passed-over maps, and a node alone in its cluster has to start with no room at all.
+    */
+    #[test]
+    fn node_count_hint_presizes_peer_maps() -> anyhow::Result<()> {
+        const HINT: usize = 64;
+        let build = |node_ids: &[&str], hint: Option<usize>| -> anyhow::Result<BroadcastNode> {
+            let mut net = Network::new(Init {
+                node_id: NodeId::from("n0"),
+                node_ids: node_ids.iter().map(|id| NodeId::from(*id)).collect(),
+            })?;
+            if let Some(hint) = hint {
+                net = net.with_node_count_hint(hint);
+            }
+            Ok(BroadcastNode::with_config(
+                net,
+                mpsc::channel().0,
+                BroadcastConfig::default(),
+            ))
+        };
+        let capacities = |node: &BroadcastNode| {
+            [
+                node.known.capacity(),
+                node.backoff.capacity(),
+                node.passed_over.capacity(),
+            ]
+        };
+        let hinted = capacities(&build(&["n0", "n1"], Some(HINT))?);
+        assert!(
+            hinted.iter().all(|capacity| *capacity >= HINT - 1),
+            "hinted at {} nodes, the per-peer maps have room for {:?}",
+            HINT,
+            hinted
+        );
+        assert_eq!(capacities(&build(&["n0"], None)?), [0; 3]);
+        Ok(())
+    }
+
+    /*
+    A provided topology that lists the node itself and a node outside node_ids among its neighbors has to be answered
+    with topology_ok as usual, and leave only the real peers, in the order given, as the node's neighbors.
+    */
+    #[test]
+    fn provided_topology_keeps_only_real_peers() -> anyhow::Result<()> {
+        let mut node: BroadcastNode = test_harness::init("n0", &["n0", "n1", "n2"])?;
+        node.topology_strategy = TopologyStrategy::UseProvided;
+        let listed = ["n2", "n0", "n9", "n1"].map(NodeId::from).to_vec();
+        let topology = HashMap::from([
+            (NodeId::from("n0"), listed.clone()),
+            (NodeId::from("n1"), vec![NodeId::from("n0")]),
+        ]);
+        let request = Event::Message(Message {
+            src: NodeId::from("c1"),
+            dest: NodeId::from("n0"),
+            body: MessageBody {
+                msg_id: Some(MsgId(1)),
+                in_reply_to: None,
+                payload: BroadcastPayload::Topology { topology },
+            },
+        });
+        let replies = test_harness::drive(&mut node, vec![request])?;
+        let acked = matches!(
+            replies.as_slice(),
+            [Message {
+                body: MessageBody {
+                    payload: BroadcastPayload::TopologyOk {},
+                    ..
+                },
+                ..
+            }]
+        );
+        assert!(acked, "given {:?} the node replied {:?}", listed, replies);
+        assert_eq!(node.neighbors, ["n2", "n1"].map(NodeId::from));
+        Ok(())
+    }
+
+    /*
+    Builds the hypercube overlay for every cluster size up to 64 and walks it breadth-first from each node: with a
+    power of two N every node has to be reachable within log2(N) hops, otherwise (the grid fallback) within the grid's
+    width + height. Neighbors also have to be mutual, since gossip acks flow back over the same links.
+    */
+    #[test]
+    fn hypercube_keeps_every_node_within_log_n_hops() {
+        const LARGEST: usize = 64;
+        for count in 1..=LARGEST {
+            let overlay: Vec<Vec<usize>> = (0..count)
+                .map(|me| {
+                    TopologyStrategy::Hypercube
+                        .overlay(count, me)
+                        .unwrap_or_default()
+                })
+                .collect();
+            for (me, neighbors) in overlay.iter().enumerate() {
+                for other in neighbors {
+                    assert!(
+                        overlay[*other].contains(&me),
+                        "with {} nodes {} neighbors {} but not the other way round",
+                        count,
+                        me,
+                        other
+                    );
+                }
+            }
+            let bound = if count.is_power_of_two() {
+                count.trailing_zeros() as usize
+            } else {
+                let width = (1..).find(|width| width * width >= count).unwrap_or(1);
+                width + count.div_ceil(width)
+            };
+            for start in 0..count {
+                let mut hops = vec![None; count];
+                hops[start] = Some(0);
+                let mut queue = VecDeque::from([start]);
+                while let Some(node) = queue.pop_front() {
+                    for &next in &overlay[node] {
+                        if hops[next].is_none() {
+                            hops[next] = hops[node].map(|h| h + 1);
+                            queue.push_back(next);
+                        }
+                    }
+                }
+                if let Some(far) = (0..count).find(|i| hops[*i].is_none_or(|h| h > bound)) {
+                    panic!(
+                        "with {} nodes {} is {:?} hops from {}, more than {}",
+                        count, far, hops[far], start, bound
+                    );
+                }
+            }
+        }
+    }
+
+    // Broadcasts two values across three nodes and checks n0's debug snapshot describes what it holds
+    #[cfg(feature = "debug")]
+    #[test]
+    fn debug_snapshot_describes_the_node() -> anyhow::Result<()> {
+        let mut cluster: TestCluster = cluster(3, BroadcastConfig::default)?;
+        let n0 = NodeId::from("n0");
+        for message in [1, 2] {
+            cluster.request(&n0, BroadcastPayload::Broadcast { message })?;
+        }
+        cluster.deliver_all()?;
+        cluster.take_client_replies();
+        cluster.request(&n0, BroadcastPayload::Debug {})?;
+        let snapshot = cluster
+            .take_client_replies()
+            .into_iter()
+            .find_map(|reply| match reply.body.payload {
+                BroadcastPayload::DebugOk { snapshot } => Some(snapshot),
+                _ => None,
+            })
+            .context("n0 didn't answer a debug request")?;
+        assert!(
+            snapshot.node_id == n0 && snapshot.peers.len() == 2 && snapshot.set_size == Some(2),
+            "n0 reported {:?}",
+            snapshot
+        );
+        Ok(())
+    }
+
+    /*
+    In quorum mode a five-node read only returns values at least three nodes are known to hold: the reader itself plus
+    the neighbors that have sent or acked them. A value short of that shows up once one more neighbor acks it.
+    */
+    #[test]
+    fn quorum_reads_only_return_majority_acked_values() -> anyhow::Result<()> {
+        let ids: Vec<NodeId> = (0..5).map(|i| NodeId::from(format!("n{}", i))).collect();
+        let init = Init {
+            node_id: ids[0].clone(),
+            node_ids: ids.clone(),
+        };
+        let config = BroadcastConfig {
+            read_mode: ReadMode::Quorum,
+            ..BroadcastConfig::default()
+        };
+        let mut node: BroadcastNode =
+            BroadcastNode::with_config(Network::new(init)?, mpsc::channel().0, config);
+        node.messages.extend([1, 2, 3]);
+        node.mark_known(ids[1].clone(), [1, 2]);
+        node.mark_known(ids[2].clone(), [1]);
+        assert_eq!([1, 2, 3].map(|message| node.ack_count(&message)), [3, 2, 1]);
+        assert_eq!(node.read(), [1]);
+
+        node.mark_known(ids[3].clone(), [2]);
+        let mut read = node.read();
+        read.sort();
+        assert_eq!(read, [1, 2]);
+        Ok(())
+    }
+
+    /*
+    Two nodes are cut apart while n0 holds a value n1 lacks. Every unacked round n0 sends has to grow its backoff for
+    n1, each wait staying between half and all of its capped ceiling, and once the cut heals the first ack has to clear
+    it.
+    */
+    #[test]
+    fn backoff_grows_then_resets_on_ack() -> anyhow::Result<()> {
+        let mut cluster = cluster(2, BroadcastConfig::default)?;
+        let [n0, n1] = [0, 1].map(|i| cluster.node_ids()[i].clone());
+        set_topology(&mut cluster, line(2))?;
+        cluster.partition(&[&["n0"]]);
+        cluster.request(&n0, BroadcastPayload::Broadcast { message: 1 })?;
+
+        let backoff = |cluster: &TestCluster| {
+            cluster
+                .node(&n0)
+                .and_then(|node| node.backoff.get(&n1).copied())
+        };
+        let mut failures = Vec::new();
+        for _ in 0..8 * MAX_BACKOFF_TICKS {
+            cluster.deliver_all()?;
+            cluster.tick(InjectedPayload::Gossip)?;
+            let Some(Backoff {
+                failures: now,
+                skip_ticks,
+            }) = backoff(&cluster)
+            else {
+                bail!("n0 has no backoff for n1 while cut off from it");
+            };
+            if failures.last() != Some(&now) {
+                let ceiling = (1 << (now - 1).min(31)).min(MAX_BACKOFF_TICKS);
+                assert!(
+                    (ceiling / 2..=ceiling).contains(&skip_ticks),
+                    "waits {} after {} failures",
+                    skip_ticks,
+                    now
+                );
+                failures.push(now);
+            }
+        }
+        assert!(failures.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(failures.len() >= 5, "only backed off {:?}", failures);
+
+        cluster.heal();
+        cluster.deliver_all()?;
+        for _ in 0..=MAX_BACKOFF_TICKS {
+            cluster.tick(InjectedPayload::Gossip)?;
+            cluster.deliver_all()?;
+        }
+        assert_eq!(backoff(&cluster), None);
+        assert_eq!(read_node(&mut cluster, &n1)?, [1]);
+        Ok(())
+    }
+
+    fn read_node<V: BroadcastValue>(
+        cluster: &mut TestCluster<V>,
+        id: &NodeId,
+    ) -> anyhow::Result<Vec<V>> {
+        cluster.take_client_replies();
+        cluster.request(id, BroadcastPayload::Read { from: None })?;
+        let read =
+            cluster
+                .take_client_replies()
+                .into_iter()
+                .find_map(|reply| match reply.body.payload {
+                    BroadcastPayload::ReadOk { messages, .. } => Some(messages),
+                    _ => None,
+                });
+        read.with_context(|| format!("{} didn't answer a read", id))
+    }
+
+    // Reads every node in the cluster, describing each one that's missing some of `expected`
+    fn lagging_nodes<V: BroadcastValue>(
+        cluster: &mut TestCluster<V>,
+        expected: &HashSet<V>,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut lagging = Vec::new();
+        for id in cluster.node_ids().to_vec() {
+            let read: HashSet<V> = read_node(cluster, &id)?.into_iter().collect();
+            let missing = expected.difference(&read).count();
+            if missing > 0 {
+                lagging.push(format!("{} is missing {}", id, missing));
+            }
+        }
+        Ok(lagging)
+    }
+}
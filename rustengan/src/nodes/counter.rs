@@ -0,0 +1,220 @@
+use crate::crdt::PnCounter;
+use crate::kv::{self, CasOutcome, KvClient, KvError};
+use crate::*;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum CounterPayload {
+    Add {
+        delta: i64,
+    },
+    AddOk {},
+    Read {},
+    ReadOk {
+        value: i64,
+    },
+    // Full per-node counts pushed between nodes in crdt mode; fire-and-forget, so there's no _ok
+    CounterGossip {
+        counts: HashMap<String, u64>,
+        // Decrements, kept apart so the increments stay a plain G-counter
+        #[serde(default)]
+        negative_counts: HashMap<String, u64>,
+    },
+}
+
+// The seq-kv key every kv-mode node keeps the shared count under
+pub const COUNTER_KEY: &str = "counter";
+
+/* Events the node schedules for itself */
+#[derive(Debug, Clone)]
+pub enum InjectedPayload {
+    Gossip, // Push our counter state to every peer (crdt mode only)
+}
+
+// How often a crdt-mode node pushes its counter state to every peer; a healed partition converges within a tick or two
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(500);
+
+/*
+Where the counter lives; set with the COUNTER_MODE env var ("crdt", the default, or "kv").
+crdt keeps a PN-counter on every node and gossips it peer-to-peer, so adds (including negative deltas) never wait on
+another service and keep working through partitions, at the cost of eventually consistent reads.
+kv keeps a single shared value in seq-kv and updates it with a CAS loop. seq-kv may answer a read with a stale value,
+so a node never reports less than the highest value it has itself committed or read (see read_floor).
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CounterMode {
+    Kv,
+    Crdt,
+}
+
+impl FromStr for CounterMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "kv" => Ok(CounterMode::Kv),
+            "crdt" => Ok(CounterMode::Crdt),
+            _ => bail!("Unknown counter mode {:?}; expected kv or crdt", s),
+        }
+    }
+}
+
+/* Node in distributed system that handles the counter, stored in Maelstrom's seq-kv service or as a PN-counter CRDT */
+pub struct CounterNode {
+    net: Network,
+    mode: CounterMode,
+    kv: KvClient,
+    crdt: PnCounter,
+    // kv mode: the highest value this node has CAS'd or read, so a stale seq-kv read can't make a client that added
+    // here see its add undone. Only sound while the counter only grows, so a negative delta here drops it for good.
+    read_floor: Option<i64>,
+    grow_only: bool,
+}
+
+impl CounterNode {
+    fn read_counter(&self, output: &mut impl Write) -> anyhow::Result<i64> {
+        match self.kv.read(&self.net, COUNTER_KEY, output) {
+            Ok(value) => Ok(value),
+            // Nobody has added anything yet
+            Err(e) if matches!(e.downcast_ref(), Some(KvError::KeyDoesNotExist(_))) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Raises read_floor to `value`, unless the counter has gone down before
+    fn observe(&mut self, value: i64) {
+        if self.grow_only {
+            self.read_floor = Some(self.read_floor.map_or(value, |floor| floor.max(value)));
+        }
+    }
+
+    // seq-kv's value, or the highest one we've seen if that's higher (its read was stale)
+    fn read_monotonic(&mut self, output: &mut impl Write) -> anyhow::Result<i64> {
+        let value = self.read_counter(output)?;
+        self.observe(value);
+        Ok(self.read_floor.map_or(value, |floor| floor.max(value)))
+    }
+
+    // Returns false if every CAS attempt lost to a concurrent writer
+    fn add(&mut self, delta: i64, output: &mut impl Write) -> anyhow::Result<bool> {
+        if delta < 0 {
+            self.grow_only = false;
+            self.read_floor = None;
+        }
+        let outcome = self
+            .kv
+            .cas_update(&self.net, COUNTER_KEY, 0, output, |current| {
+                Some(current + delta)
+            })?;
+        match outcome {
+            CasOutcome::Updated { to, .. } => {
+                self.observe(to);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn handle_message(
+        &mut self,
+        input: Message<CounterPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        match &input.body.payload {
+            CounterPayload::Add { delta } if self.mode == CounterMode::Crdt => {
+                self.crdt.increment(self.net.node_id.as_str(), *delta);
+                self.net.reply(&input, CounterPayload::AddOk {}, output)?;
+            }
+            CounterPayload::Add { delta } => {
+                if self.add(*delta, output)? {
+                    self.net.reply(&input, CounterPayload::AddOk {}, output)?;
+                } else {
+                    let text = format!("add failed after {} CAS attempts", kv::MAX_CAS_ATTEMPTS);
+                    self.net.reply_error(
+                        &input,
+                        error_code::TEMPORARILY_UNAVAILABLE,
+                        text,
+                        output,
+                    )?;
+                }
+            }
+            CounterPayload::Read { .. } => {
+                let value = match self.mode {
+                    CounterMode::Kv => self.read_monotonic(output)?,
+                    CounterMode::Crdt => self.crdt.value(),
+                };
+                self.net
+                    .reply(&input, CounterPayload::ReadOk { value }, output)?;
+            }
+            CounterPayload::CounterGossip {
+                counts,
+                negative_counts,
+            } => {
+                self.crdt.positive.merge(counts);
+                self.crdt.negative.merge(negative_counts);
+            }
+            CounterPayload::AddOk { .. } | CounterPayload::ReadOk { .. } => {
+                self.net.reject_unsupported(&input, output)?;
+            }
+        };
+
+        Ok(())
+    }
+
+    // Pushes our full counts to every peer; merging is idempotent, so lost or repeated rounds don't matter
+    fn gossip(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        for peer in self.net.peers() {
+            let payload = CounterPayload::CounterGossip {
+                counts: self.crdt.positive.counts.clone(),
+                negative_counts: self.crdt.negative.counts.clone(),
+            };
+            self.net.send(peer.clone(), payload, output)?;
+        }
+        Ok(())
+    }
+}
+
+impl Node<CounterPayload, InjectedPayload> for CounterNode {
+    fn from_init(
+        net: Network,
+        inject: mpsc::Sender<Event<CounterPayload, InjectedPayload>>,
+    ) -> anyhow::Result<Self> {
+        let crdt = PnCounter::with_capacity(net.node_count_hint());
+        let node = CounterNode {
+            net,
+            mode: match std::env::var("COUNTER_MODE") {
+                Ok(mode) => mode.parse()?,
+                Err(_) => CounterMode::Crdt,
+            },
+            kv: KvClient::seq(),
+            crdt,
+            read_floor: None,
+            grow_only: true,
+        };
+        if node.mode == CounterMode::Crdt {
+            spawn_ticker(inject, GOSSIP_INTERVAL, InjectedPayload::Gossip);
+        }
+        Ok(node)
+    }
+
+    fn step(
+        &mut self,
+        event: Event<CounterPayload, InjectedPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::Message(input) => self.handle_message(input, output),
+            Event::Injected(InjectedPayload::Gossip) => self.gossip(output),
+            Event::Eof => Ok(()),
+        }
+    }
+}
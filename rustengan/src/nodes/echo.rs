@@ -0,0 +1,73 @@
+use crate::*;
+
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::mpsc;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum EchoPayload {
+    Echo { echo: String },
+    EchoOk { echo: String },
+}
+
+pub struct EchoNode {
+    // Node in distributed system that handles echo functionality
+    net: Network,
+}
+
+impl Node<EchoPayload> for EchoNode {
+    fn from_init(net: Network, _inject: mpsc::Sender<Event<EchoPayload>>) -> anyhow::Result<Self> {
+        Ok(EchoNode { net })
+    }
+
+    fn step(&mut self, event: Event<EchoPayload>, output: &mut impl Write) -> anyhow::Result<()> {
+        let Event::Message(input) = event else {
+            return Ok(());
+        };
+        match &input.body.payload {
+            EchoPayload::Echo { echo } => {
+                let payload = EchoPayload::EchoOk { echo: echo.clone() };
+                self.net.reply(&input, payload, output)?;
+                // Clients wait on every echo_ok, so don't let it sit out a stdout coalescing window
+                output.flush()?;
+            }
+            EchoPayload::EchoOk { .. } => {
+                self.net.reject_unsupported(&input, output)?;
+            }
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stepped against an in-memory buffer, an echo has to write exactly one echo_ok carrying its text back
+    #[test]
+    fn echo_is_answered_into_a_buffer() -> anyhow::Result<()> {
+        let mut node: EchoNode = test_harness::init("n0", &["n0"])?;
+        let echo = Message {
+            src: NodeId::from("c1"),
+            dest: NodeId::from("n0"),
+            body: MessageBody {
+                msg_id: Some(MsgId(7)),
+                in_reply_to: None,
+                payload: EchoPayload::Echo {
+                    echo: "hello".to_string(),
+                },
+            },
+        };
+        let mut output = Vec::new();
+        node.step(Event::Message(echo), &mut output)?;
+
+        let reply: Message<EchoPayload> = serde_json::from_slice(&output)?;
+        assert_eq!(reply.dest, NodeId::from("c1"));
+        assert_eq!(reply.body.in_reply_to, Some(MsgId(7)));
+        assert!(matches!(reply.body.payload, EchoPayload::EchoOk { echo } if echo == "hello"));
+        Ok(())
+    }
+}
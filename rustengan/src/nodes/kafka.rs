@@ -0,0 +1,584 @@
+use crate::failure::{self, FailureDetector};
+use crate::kv::{self, CasOutcome, KvClient, KvError};
+use crate::nodes::lin_kv;
+use crate::ring::HashRing;
+use crate::*;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::mpsc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KafkaPayload {
+    Send {
+        key: String,
+        msg: i64,
+    },
+    SendOk {
+        offset: usize,
+    },
+    Poll {
+        offsets: HashMap<String, usize>,
+    },
+    PollOk {
+        // Maelstrom expects each entry as a two-element [offset, msg] array, which is how serde writes tuples
+        msgs: HashMap<String, Vec<(usize, i64)>>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, usize>,
+    },
+    CommitOffsetsOk {},
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, usize>,
+    },
+    // Heartbeat, so the failure detector can tell which owners are still answering
+    Ping {},
+    Pong {},
+    // Not part of Maelstrom: asks for a snapshot of the node's state (debug feature only)
+    #[cfg(feature = "debug")]
+    Debug {},
+    #[cfg(feature = "debug")]
+    DebugOk {
+        #[serde(flatten)]
+        snapshot: DebugSnapshot,
+    },
+}
+
+/* Events the node schedules for itself */
+#[derive(Debug, Clone)]
+pub enum InjectedPayload {
+    SweepCallbacks, // Time out proxied requests whose owner never answered
+    Heartbeat,      // Ping every peer, every heartbeat interval
+}
+
+// How long to wait for a key's owner to answer a proxied request before failing the client's request
+const PROXY_TIMEOUT: Duration = Duration::from_secs(2);
+const SWEEP_INTERVAL: Duration = Duration::from_millis(500);
+// Each polled entry is its own kv read, so cap how many a single poll fetches per key; override with the
+// KAFKA_MAX_POLL_ENTRIES env var. Clients page through a longer log by polling again from the last offset they got.
+const DEFAULT_MAX_POLL_ENTRIES: usize = 100;
+
+/*
+Node in distributed system that handles the Kafka-style log.
+Everything lives in Maelstrom's lin-kv service:
+- next/<key>: the next offset to hand out for a key
+- log/<key>/<offset>: the message stored at that offset
+- commit/<key>: the committed offset for a key
+Each key is also owned by one node (see owner_of). Sends, polls and commits for a key are proxied to its owner, so
+only the owner ever CASes that key's counters and the racing CAS retries become the exception rather than the rule.
+While an owner is suspected down, its keys move to the next live node; the CAS retries keep that safe if the owner
+was only slow and still answers for them too.
+*/
+pub struct KafkaNode {
+    net: Network,
+    kv: KvClient,
+    callbacks: Callbacks<KafkaNode, KafkaPayload>,
+    max_poll_entries: usize,
+    detector: FailureDetector,
+    ring: HashRing, // Who owns each key; see ring::HashRing
+    // Offsets this node has committed as a key's owner; the real ones live in lin-kv, this is only for debugging
+    committed: HashMap<String, usize>,
+}
+
+impl KafkaNode {
+    /*
+    The lin-kv contents a node would leave behind after appending each of `logs` in order, for starting a test or
+    simulation from known logs (see simulation::Process::spawn_with_state) instead of replaying the sends.
+    */
+    pub fn kv_state_with_logs(
+        logs: &HashMap<String, Vec<i64>>,
+    ) -> HashMap<lin_kv::Value, lin_kv::Value> {
+        let mut state = HashMap::new();
+        for (key, log) in logs {
+            state.insert(
+                lin_kv::Value::Str(next_offset_key(key)),
+                lin_kv::Value::Int(log.len() as i64),
+            );
+            for (offset, msg) in log.iter().enumerate() {
+                state.insert(
+                    lin_kv::Value::Str(entry_key(key, offset)),
+                    lin_kv::Value::Int(*msg),
+                );
+            }
+        }
+        state
+    }
+
+    /*
+    Appends `msg` to `key`'s log at the first free offset: reads the next/<key> counter, creates log/<key>/<offset>
+    there with a CAS that only succeeds if nothing is stored at that offset yet, then moves the counter past it. The
+    entry is stored before the counter moves, so a failed write leaves no hole for log_read to stop at; it's answered
+    with the store's error and nothing else has to be undone. When two nodes race for the same offset only one creates
+    it; the loser moves the counter on for it and tries the next one. Returns None if every attempt lost.
+    */
+    fn log_append(
+        &self,
+        key: &str,
+        msg: i64,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Option<usize>> {
+        for _ in 0..kv::MAX_CAS_ATTEMPTS {
+            // A missing counter is a key nothing's been sent to yet, so its first offset is 0
+            let offset = match self.kv.read(&self.net, next_offset_key(key), output) {
+                Ok(next) => next,
+                Err(e) if matches!(e.downcast_ref(), Some(KvError::KeyDoesNotExist(_))) => 0,
+                Err(e) => return Err(e),
+            };
+            // Entries are always integers, so none ever matches UNCLAIMED and the CAS can only create the entry
+            match self.kv.cas(
+                &self.net,
+                entry_key(key, offset),
+                UNCLAIMED,
+                msg,
+                true,
+                output,
+            ) {
+                Ok(()) => {
+                    // The entry is in the log either way; if this fails the next append finds the offset taken
+                    // and moves the counter on itself
+                    if let Err(e) = self.advance_next_offset(key, offset, output) {
+                        eprintln!("Couldn't move {} past offset {}: {:#}", key, offset, e);
+                    }
+                    return Ok(Some(offset));
+                }
+                Err(e) if matches!(e.downcast_ref(), Some(KvError::PreconditionFailed(_))) => {
+                    self.advance_next_offset(key, offset, output)?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    // Moves next/<key> from `offset` to the one after it, unless another node already has
+    fn advance_next_offset(
+        &self,
+        key: &str,
+        offset: usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        // The counter only goes missing before the first entry, and then `offset` is 0
+        match self.kv.cas(
+            &self.net,
+            next_offset_key(key),
+            offset,
+            offset + 1,
+            offset == 0,
+            output,
+        ) {
+            Err(e) if matches!(e.downcast_ref(), Some(KvError::PreconditionFailed(_))) => Ok(()),
+            result => result,
+        }
+    }
+
+    /*
+    Entries from `offset` up to the end of the log (at most max_poll_entries of them), none if `offset` is at or past
+    the end or nothing was ever sent to `key`. The end is the first offset with no entry rather than next/<key>, which
+    can lag behind an entry whose append didn't get to move it.
+    */
+    fn log_read(
+        &self,
+        key: &str,
+        offset: usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Vec<(usize, i64)>> {
+        // Saturating, since a client can send any offset at all
+        let end = offset.saturating_add(self.max_poll_entries);
+        let mut entries = Vec::new();
+        for offset in offset..end {
+            match self.kv.read(&self.net, entry_key(key, offset), output) {
+                Ok(msg) => entries.push((offset, msg)),
+                Err(e) if matches!(e.downcast_ref(), Some(KvError::KeyDoesNotExist(_))) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read_committed(&self, key: &str, output: &mut impl Write) -> anyhow::Result<Option<usize>> {
+        match self.kv.read(&self.net, committed_key(key), output) {
+            Ok(offset) => Ok(Some(offset)),
+            // Nothing has been committed for this key yet
+            Err(e) if matches!(e.downcast_ref(), Some(KvError::KeyDoesNotExist(_))) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Raise the committed offset for a key to at least `offset`; commits never move backwards, so a late or
+    // duplicated commit of a lower offset is a no-op that still succeeds. The CAS makes that hold when two nodes
+    // commit the same key at once. Returns false if every CAS attempt lost to a concurrent commit.
+    fn commit(
+        &mut self,
+        key: &str,
+        offset: usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<bool> {
+        // Defaulting to -1 rather than 0 means a first commit of offset 0 still creates the key
+        let outcome = self.kv.cas_update(
+            &self.net,
+            committed_key(key),
+            -1,
+            output,
+            |current: &i64| (*current < offset as i64).then_some(offset as i64),
+        )?;
+        let committed = match outcome {
+            CasOutcome::Updated { to, .. } | CasOutcome::Unchanged(to) => to,
+            CasOutcome::Contended => return Ok(false),
+        };
+        self.committed.insert(key.to_string(), committed as usize);
+        Ok(true)
+    }
+
+    // The key's owner on the hash ring, or if that's suspected, the first live node after it on the ring. We never
+    // suspect ourselves, and we're on the ring, so there always is one.
+    fn owner_of(&self, key: &str) -> &NodeId {
+        self.ring
+            .successors(key)
+            .find(|node| !self.detector.is_suspected(node))
+            .unwrap_or(&self.net.node_id)
+    }
+
+    fn heartbeat(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let (peers, suspected) = self.detector.heartbeat();
+        for peer in suspected {
+            eprintln!("Suspecting {}: its keys move to the next live node", peer);
+        }
+        for peer in peers {
+            self.net.send(peer, KafkaPayload::Ping {}, output)?;
+        }
+        Ok(())
+    }
+
+    // Splits a keyed request into one request per owning node, each carrying only that owner's keys
+    fn split_by_owner(&self, request: &KafkaPayload) -> HashMap<NodeId, KafkaPayload> {
+        let mut parts = HashMap::new();
+        match request {
+            KafkaPayload::Send { key, .. } => {
+                parts.insert(self.owner_of(key).clone(), request.clone());
+            }
+            KafkaPayload::Poll { offsets } | KafkaPayload::CommitOffsets { offsets } => {
+                let mut grouped: HashMap<NodeId, HashMap<String, usize>> = HashMap::new();
+                for (key, offset) in offsets {
+                    grouped
+                        .entry(self.owner_of(key).clone())
+                        .or_default()
+                        .insert(key.clone(), *offset);
+                }
+                for (owner, offsets) in grouped {
+                    let part = match request {
+                        KafkaPayload::Poll { .. } => KafkaPayload::Poll { offsets },
+                        _ => KafkaPayload::CommitOffsets { offsets },
+                    };
+                    parts.insert(owner, part);
+                }
+            }
+            _ => {}
+        }
+        parts
+    }
+
+    // Handles the part of a request whose keys we own. Err is the text of a retryable failure for the client.
+    fn handle_owned(
+        &mut self,
+        request: &KafkaPayload,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Result<KafkaPayload, String>> {
+        Ok(match request {
+            KafkaPayload::Send { key, msg } => match self.log_append(key, *msg, output)? {
+                Some(offset) => Ok(KafkaPayload::SendOk { offset }),
+                None => Err(format!(
+                    "send to {} failed after {} CAS attempts",
+                    key,
+                    kv::MAX_CAS_ATTEMPTS
+                )),
+            },
+            KafkaPayload::Poll { offsets } => {
+                let mut msgs = HashMap::new();
+                // Every polled key is in the reply, with no entries if there's nothing at or after its offset
+                for (key, offset) in offsets {
+                    msgs.insert(key.clone(), self.log_read(key, *offset, output)?);
+                }
+                Ok(KafkaPayload::PollOk { msgs })
+            }
+            KafkaPayload::CommitOffsets { offsets } => {
+                for (key, offset) in offsets {
+                    if !self.commit(key, *offset, output)? {
+                        return Ok(Err(format!(
+                            "commit of {} failed after {} CAS attempts",
+                            key,
+                            kv::MAX_CAS_ATTEMPTS
+                        )));
+                    }
+                }
+                Ok(KafkaPayload::CommitOffsetsOk {})
+            }
+            other => anyhow::bail!("{:?} isn't a keyed request", other),
+        })
+    }
+
+    /*
+    Answers a Send/Poll/CommitOffsets: our own keys are handled here, and every other owner gets the same request
+    narrowed to its keys. Owners are all asked at once without blocking (two nodes proxying to each other at once
+    would otherwise deadlock), and the client is answered once the last part arrives or the shared deadline passes.
+    */
+    fn route(
+        &mut self,
+        input: Message<KafkaPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let mut parts = self.split_by_owner(&input.body.payload);
+        let response = match parts.remove(&self.net.node_id) {
+            Some(owned) => match self.handle_owned(&owned, output)? {
+                Ok(response) => response,
+                Err(text) => {
+                    return self.net.reply_error(
+                        &input,
+                        error_code::TEMPORARILY_UNAVAILABLE,
+                        text,
+                        output,
+                    )
+                }
+            },
+            None => empty_response(&input.body.payload),
+        };
+        if parts.is_empty() {
+            return self.net.reply(&input, response, output);
+        }
+
+        let requests = parts.into_iter().collect();
+        self.net.send_all_with_callback(
+            &mut self.callbacks,
+            requests,
+            PROXY_TIMEOUT,
+            output,
+            Box::new(move |node: &mut KafkaNode, replies, mut output| {
+                node.relay(input, response, replies, &mut output)
+            }),
+        )
+    }
+
+    // Merges the owners' answers and replies to the client (with its original msg_id as in_reply_to), or, if any
+    // owner didn't answer in time, fails the whole request with a timeout error (code 0, since those owners may still
+    // have applied their part) naming every owner that didn't
+    fn relay(
+        &mut self,
+        input: Message<KafkaPayload>,
+        mut response: KafkaPayload,
+        replies: Vec<(NodeId, anyhow::Result<Message<KafkaPayload>>)>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let mut failures = Vec::new();
+        for (owner, reply) in replies {
+            match reply {
+                Ok(reply) => merge_response(&mut response, reply.body.payload),
+                Err(e) => failures.push(format!("{} ({})", owner, e)),
+            }
+        }
+        if !failures.is_empty() {
+            let text = format!("key owners didn't answer: {}", failures.join(", "));
+            return self
+                .net
+                .reply_error(&input, error_code::TIMEOUT, text, output);
+        }
+        self.net.reply(&input, response, output)
+    }
+
+    fn handle_message(
+        &mut self,
+        input: Message<KafkaPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        if let Some(callback) = self.callbacks.take(&input) {
+            return callback(self, Ok(input), output);
+        }
+
+        match &input.body.payload {
+            KafkaPayload::Send { .. }
+            | KafkaPayload::Poll { .. }
+            | KafkaPayload::CommitOffsets { .. } => {
+                self.route(input, output)?;
+            }
+            // Committed offsets are read straight from lin-kv, which any node can do
+            KafkaPayload::ListCommittedOffsets { keys } => {
+                // Only keys with a commit get an entry: a client reads an absent key as uncommitted, and 0 would
+                // claim offset 0 was consumed
+                let mut offsets = HashMap::new();
+                for key in keys {
+                    if let Some(offset) = self.read_committed(key, output)? {
+                        offsets.insert(key.clone(), offset);
+                    }
+                }
+                self.net.reply(
+                    &input,
+                    KafkaPayload::ListCommittedOffsetsOk { offsets },
+                    output,
+                )?;
+            }
+            KafkaPayload::Ping {} => {
+                self.net.reply(&input, KafkaPayload::Pong {}, output)?;
+            }
+            KafkaPayload::Pong {} => {
+                if self.detector.pong(&input.src) {
+                    eprintln!("{} is answering again and owns its keys again", input.src);
+                }
+            }
+            #[cfg(feature = "debug")]
+            KafkaPayload::Debug {} => {
+                let mut snapshot = self.net.debug_snapshot(self.callbacks.len());
+                snapshot.committed_offsets = Some(self.committed.clone());
+                self.net
+                    .reply(&input, KafkaPayload::DebugOk { snapshot }, output)?;
+            }
+            #[cfg(feature = "debug")]
+            KafkaPayload::DebugOk { .. } => {
+                self.net.reject_unsupported(&input, output)?;
+            }
+            KafkaPayload::SendOk { .. }
+            | KafkaPayload::PollOk { .. }
+            | KafkaPayload::CommitOffsetsOk { .. }
+            | KafkaPayload::ListCommittedOffsetsOk { .. } => {
+                self.net.reject_unsupported(&input, output)?;
+            }
+        };
+
+        Ok(())
+    }
+}
+
+// The reply a client gets when none of its keys are handled locally, before the owners' parts are merged in
+fn empty_response(request: &KafkaPayload) -> KafkaPayload {
+    match request {
+        KafkaPayload::Poll { .. } => KafkaPayload::PollOk {
+            msgs: HashMap::new(),
+        },
+        KafkaPayload::CommitOffsets { .. } => KafkaPayload::CommitOffsetsOk {},
+        // A send has a single key, so its only part is the owner's and that reply replaces this one
+        _ => KafkaPayload::SendOk { offset: 0 },
+    }
+}
+
+fn merge_response(response: &mut KafkaPayload, part: KafkaPayload) {
+    match (response, part) {
+        (KafkaPayload::PollOk { msgs }, KafkaPayload::PollOk { msgs: more }) => msgs.extend(more),
+        (KafkaPayload::CommitOffsetsOk {}, KafkaPayload::CommitOffsetsOk {}) => {}
+        (response @ KafkaPayload::SendOk { .. }, part @ KafkaPayload::SendOk { .. }) => {
+            *response = part
+        }
+        (response, part) => eprintln!("Can't merge {:?} into {:?}", part, response),
+    }
+}
+
+// What log_append's CAS expects to find at a free offset; a string, so it never equals a stored entry
+const UNCLAIMED: &str = "unclaimed";
+
+fn next_offset_key(key: &str) -> String {
+    format!("next/{}", key)
+}
+
+fn entry_key(key: &str, offset: usize) -> String {
+    format!("log/{}/{}", key, offset)
+}
+
+fn committed_key(key: &str) -> String {
+    format!("commit/{}", key)
+}
+
+impl Node<KafkaPayload, InjectedPayload> for KafkaNode {
+    fn from_init(
+        net: Network,
+        inject: mpsc::Sender<Event<KafkaPayload, InjectedPayload>>,
+    ) -> anyhow::Result<Self> {
+        let max_poll_entries = match std::env::var("KAFKA_MAX_POLL_ENTRIES") {
+            Ok(max) => match max.parse() {
+                Ok(0) | Err(_) => bail!(
+                    "KAFKA_MAX_POLL_ENTRIES must be a positive number, got {}",
+                    max
+                ),
+                Ok(max) => max,
+            },
+            Err(_) => DEFAULT_MAX_POLL_ENTRIES,
+        };
+        let detector = FailureDetector::from_env(net.peers())?;
+        let ring = HashRing::for_cluster(&net)?;
+        spawn_ticker(
+            inject.clone(),
+            failure::heartbeat_interval()?,
+            InjectedPayload::Heartbeat,
+        );
+        spawn_ticker(inject, SWEEP_INTERVAL, InjectedPayload::SweepCallbacks);
+        Ok(KafkaNode {
+            net,
+            kv: KvClient::lin(),
+            callbacks: Callbacks::from_env()?,
+            max_poll_entries,
+            detector,
+            ring,
+            committed: HashMap::new(),
+        })
+    }
+
+    fn step(
+        &mut self,
+        event: Event<KafkaPayload, InjectedPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::Message(input) => self.handle_message(input, output),
+            Event::Injected(InjectedPayload::SweepCallbacks) => {
+                for (callback, timeout) in self.callbacks.sweep_expired() {
+                    callback(self, Err(timeout.into()), output)?;
+                }
+                Ok(())
+            }
+            Event::Injected(InjectedPayload::Heartbeat) => self.heartbeat(output),
+            Event::Eof => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A poll whose only other owner never answered has to fail with a timeout (code 0), not as unavailable
+    #[test]
+    fn owner_timeout_is_a_timeout_error() -> anyhow::Result<()> {
+        let mut node: KafkaNode = test_harness::init("n0", &["n0", "n1"])?;
+        let poll = KafkaPayload::Poll {
+            offsets: HashMap::from([("k".to_string(), 0)]),
+        };
+        let input = Message {
+            src: NodeId::from("c1"),
+            dest: NodeId::from("n0"),
+            body: MessageBody {
+                msg_id: Some(MsgId(1)),
+                in_reply_to: None,
+                payload: poll.clone(),
+            },
+        };
+        let timeout = RpcTimeout {
+            dest: NodeId::from("n1"),
+            msg_id: MsgId(0),
+        };
+        let mut output = Vec::new();
+        node.relay(
+            input,
+            empty_response(&poll),
+            vec![(NodeId::from("n1"), Err(timeout.into()))],
+            &mut output,
+        )?;
+        let reply: Message<serde_json::Value> = serde_json::from_slice(&output)?;
+        assert_eq!(reply.body.in_reply_to, Some(MsgId(1)));
+        assert_eq!(reply.body.payload["type"], "error");
+        assert_eq!(reply.body.payload["code"], error_code::TIMEOUT);
+        Ok(())
+    }
+}
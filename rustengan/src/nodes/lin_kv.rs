@@ -0,0 +1,477 @@
+use crate::kv::KvPayload;
+use crate::*;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/* Events the node schedules for itself */
+#[derive(Debug, Clone)]
+pub enum InjectedPayload {
+    SweepCallbacks, // Time out proxied requests whose owner never answered
+}
+
+// How long to wait for a key's owner before telling the client its request timed out
+const PROXY_TIMEOUT: Duration = Duration::from_secs(1);
+const SWEEP_INTERVAL: Duration = Duration::from_millis(200);
+
+/*
+A key or value as Maelstrom's kv workloads send them: any JSON scalar. Values compare by what they are, not by how
+they were written, so 1.50 and 1.5 are equal, but an integer never equals a float (1 != 1.0) and neither equals a
+string ("1"). null is a value like any other: a key holding null exists.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    // Tried before Float, so a JSON number without a fraction or exponent stays an integer
+    Int(i64),
+    // Integers above i64::MAX, which would otherwise lose precision as a Float; smaller ones are always an Int
+    UInt(u64),
+    Float(Float),
+    Str(String),
+}
+
+/*
+A float that can be a map key: equal and hashed by its bits, with -0.0 folded into 0.0 as it's read so the two
+compare (and hash to an owner) the same. JSON has no NaN, so bitwise equality is value equality.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct Float(f64);
+
+impl Float {
+    pub fn new(value: f64) -> Self {
+        Float(if value == 0.0 { 0.0 } else { value })
+    }
+
+    pub fn get(self) -> f64 {
+        self.0
+    }
+}
+
+impl Eq for Float {}
+
+impl std::hash::Hash for Float {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state)
+    }
+}
+
+impl<'de> Deserialize<'de> for Float {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        f64::deserialize(deserializer).map(Float::new)
+    }
+}
+
+impl TryFrom<&serde_json::Value> for Value {
+    type Error = String;
+
+    fn try_from(value: &serde_json::Value) -> Result<Self, String> {
+        serde_json::from_value(value.clone())
+            .map_err(|_| format!("{} is not a scalar (null, bool, number or string)", value))
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::UInt(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{:?}", value.get()),
+            Value::Str(value) => write!(f, "{:?}", value),
+        }
+    }
+}
+
+/*
+Node that is itself a linearizable kv store, speaking the same read/write/cas protocol as Maelstrom's lin-kv service
+(so KvClient can be pointed at it). Every key has a single owner (see owner_of) that applies all of its operations in
+the order they arrive; other nodes proxy requests for that key to the owner, so there is exactly one copy of each
+key and every operation on it is linearizable.
+*/
+pub struct LinKvNode {
+    net: Network,
+    store: HashMap<Value, Value>,
+    callbacks: Callbacks<LinKvNode, KvPayload>,
+}
+
+impl LinKvNode {
+    /*
+    Starts the node with `state` already in its store instead of empty, for tests and simulations that want a known
+    starting point (Maelstrom always starts nodes empty). In a cluster only the keys this node owns are served from it.
+    */
+    pub fn with_state(mut self, state: HashMap<Value, Value>) -> Self {
+        self.store = state;
+        self
+    }
+
+    fn owner_of(&self, key: &Value) -> &NodeId {
+        // Hashing the JSON text keeps 1, 1.0 and "1" apart, as they are in the store
+        let key = serde_json::to_string(key).unwrap_or_default();
+        self.net.key_owner(&key)
+    }
+
+    // Applies a request for a key we own, returning the reply payload
+    fn apply(&mut self, request: &KvPayload) -> Result<KvPayload, MaelstromError> {
+        let malformed = |text| MaelstromError::new(error_code::MALFORMED_REQUEST, text);
+        match request {
+            KvPayload::Read { key } => {
+                let key = Value::try_from(key).map_err(malformed)?;
+                match self.store.get(&key) {
+                    Some(value) => Ok(KvPayload::ReadOk {
+                        value: serde_json::to_value(value).unwrap_or_default(),
+                    }),
+                    None => Err(key_does_not_exist(&key)),
+                }
+            }
+            KvPayload::Write { key, value } => {
+                let key = Value::try_from(key).map_err(malformed)?;
+                let value = Value::try_from(value).map_err(malformed)?;
+                self.store.insert(key, value);
+                Ok(KvPayload::WriteOk {})
+            }
+            KvPayload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => {
+                let key = Value::try_from(key).map_err(malformed)?;
+                let from = Value::try_from(from).map_err(malformed)?;
+                let to = Value::try_from(to).map_err(malformed)?;
+                // A key holding null exists, so create_if_not_exists doesn't apply to it and `from` has to be null
+                match self.store.get(&key) {
+                    None if *create_if_not_exists => {}
+                    None => return Err(key_does_not_exist(&key)),
+                    Some(current) if *current != from => {
+                        return Err(MaelstromError::precondition_failed().with_text(format!(
+                            "expected key {} to be {}, but it was {}",
+                            key, from, current
+                        )))
+                    }
+                    Some(_) => {}
+                }
+                self.store.insert(key, to);
+                Ok(KvPayload::CasOk {})
+            }
+            other => Err(malformed(format!("{:?} isn't a kv request", other))),
+        }
+    }
+
+    // Answers a read/write/cas here if we own its key, otherwise has the owner answer it and relays the reply
+    fn route(&mut self, input: Message<KvPayload>, output: &mut impl Write) -> anyhow::Result<()> {
+        let key = match &input.body.payload {
+            KvPayload::Read { key } | KvPayload::Write { key, .. } | KvPayload::Cas { key, .. } => {
+                key
+            }
+            _ => return self.net.reject_unsupported(&input, output),
+        };
+        let key = Value::try_from(key)
+            .map_err(|text| MaelstromError::new(error_code::MALFORMED_REQUEST, text))?;
+        let owner = self.owner_of(&key).clone();
+        if owner == self.net.node_id {
+            // A failed request comes back as a MaelstromError, which the event loop turns into the error reply
+            let reply = self.apply(&input.body.payload)?;
+            return self.net.reply(&input, reply, output);
+        }
+
+        self.net.proxy(
+            &mut self.callbacks,
+            owner,
+            input,
+            PROXY_TIMEOUT,
+            output,
+            |node: &LinKvNode| &node.net,
+        )
+    }
+
+    fn handle_message(
+        &mut self,
+        input: Message<KvPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        if let Some(callback) = self.callbacks.take(&input) {
+            return callback(self, Ok(input), output);
+        }
+
+        match &input.body.payload {
+            KvPayload::Read { .. } | KvPayload::Write { .. } | KvPayload::Cas { .. } => {
+                self.route(input, output)?;
+            }
+            // Answering an unexpected error with another error could bounce between two nodes forever
+            KvPayload::Error { .. } => {
+                eprintln!("Received unexpected error reply: {:?}", input);
+            }
+            KvPayload::ReadOk { .. } | KvPayload::WriteOk { .. } | KvPayload::CasOk { .. } => {
+                self.net.reject_unsupported(&input, output)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn key_does_not_exist(key: &Value) -> MaelstromError {
+    MaelstromError::key_does_not_exist().with_text(format!("key {} does not exist", key))
+}
+
+impl Node<KvPayload, InjectedPayload> for LinKvNode {
+    fn from_init(
+        net: Network,
+        inject: mpsc::Sender<Event<KvPayload, InjectedPayload>>,
+    ) -> anyhow::Result<Self> {
+        spawn_ticker(inject, SWEEP_INTERVAL, InjectedPayload::SweepCallbacks);
+        Ok(LinKvNode {
+            net,
+            store: HashMap::new(),
+            callbacks: Callbacks::from_env()?,
+        })
+    }
+
+    fn step(
+        &mut self,
+        event: Event<KvPayload, InjectedPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::Message(input) => self.handle_message(input, output),
+            Event::Injected(InjectedPayload::SweepCallbacks) => {
+                for (callback, timeout) in self.callbacks.sweep_expired() {
+                    callback(self, Err(timeout.into()), output)?;
+                }
+                Ok(())
+            }
+            Event::Eof => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::Cluster;
+
+    use anyhow::{bail, Context};
+
+    // Integers either side of i64::MAX have to keep every digit, as an Int up to it and a UInt past it
+    #[test]
+    fn integers_past_i64_max_stay_exact() -> anyhow::Result<()> {
+        let parse = |json: &str| serde_json::from_str::<Value>(json);
+        assert_eq!(parse("9223372036854775807")?, Value::Int(i64::MAX));
+        assert_eq!(
+            parse("9223372036854775808")?,
+            Value::UInt(i64::MAX as u64 + 1)
+        );
+        assert_eq!(parse("18446744073709551615")?, Value::UInt(u64::MAX));
+        assert!(matches!(parse("18446744073709551616")?, Value::Float(_)));
+        assert_eq!(
+            serde_json::to_string(&Value::UInt(u64::MAX))?,
+            "18446744073709551615"
+        );
+        Ok(())
+    }
+
+    /*
+    Every kind of scalar has to come back from a read exactly as written (an integer as an integer, a float as a float),
+    and a cas has to compare by value: 1.50 matches a stored 1.5, while 1 doesn't match 1.0 and "1" doesn't match 1. A
+    key holding null exists, so a create_if_not_exists cas from anything but null has to fail on it.
+    */
+    #[test]
+    fn values_keep_their_kind() -> anyhow::Result<()> {
+        use serde_json::json;
+
+        let mut node: LinKvNode = test_harness::init("n0", &["n0"])?;
+        let mut msg_id = 0;
+        let mut request = |node: &mut LinKvNode, payload| {
+            msg_id += 1;
+            let request = Event::Message(Message {
+                src: NodeId::from("c0"),
+                dest: NodeId::from("n0"),
+                body: MessageBody {
+                    msg_id: Some(MsgId(msg_id)),
+                    in_reply_to: None,
+                    payload,
+                },
+            });
+            test_harness::drive(node, vec![request])?
+                .pop()
+                .map(|reply| reply.body.payload)
+                .context("no reply")
+        };
+        let kinds = [
+            json!(null),
+            json!(true),
+            json!(1),
+            json!(1.0),
+            json!(-2.5),
+            json!(i64::MIN),
+            json!(i64::MAX),
+            json!(i64::MAX as u64 + 1),
+            json!(u64::MAX),
+            json!("1"),
+            json!(""),
+        ];
+        for (i, value) in kinds.iter().enumerate() {
+            let key = json!(format!("kind-{}", i));
+            request(
+                &mut node,
+                KvPayload::Write {
+                    key: key.clone(),
+                    value: value.clone(),
+                },
+            )?;
+            match request(&mut node, KvPayload::Read { key })? {
+                KvPayload::ReadOk { value: read } if read == *value => {}
+                other => bail!("wrote {} and read back {:?}", value, other),
+            }
+        }
+
+        // (stored value, cas from, whether the cas has to succeed)
+        let cases = [
+            (
+                json!(1.5),
+                serde_json::from_str::<serde_json::Value>("1.50")?,
+                true,
+            ),
+            (json!(1.0), json!(1), false),
+            (json!(1), json!(1.0), false),
+            (json!(1), json!("1"), false),
+            // Neighbours this big are the same f64, so this only fails if they stay exact integers
+            (json!(u64::MAX), json!(u64::MAX - 1), false),
+            (json!(-0.0), json!(0.0), true),
+            (json!(false), json!(false), true),
+            (json!(null), json!(null), true),
+            (json!(null), json!(5), false),
+        ];
+        for (i, (value, from, succeeds)) in cases.iter().enumerate() {
+            let key = json!(format!("cas-{}", i));
+            request(
+                &mut node,
+                KvPayload::Write {
+                    key: key.clone(),
+                    value: value.clone(),
+                },
+            )?;
+            let cas = KvPayload::Cas {
+                key,
+                from: from.clone(),
+                to: json!("swapped"),
+                create_if_not_exists: true,
+            };
+            let reply = request(&mut node, cas)?;
+            let swapped = match &reply {
+                KvPayload::CasOk {} => true,
+                KvPayload::Error { code, .. } if *code == error_code::PRECONDITION_FAILED => false,
+                _ => bail!("cas from {} got {:?}", from, reply),
+            };
+            if swapped != *succeeds {
+                bail!("cas from {} on a stored {} got {:?}", from, value, reply);
+            }
+        }
+        Ok(())
+    }
+
+    /*
+    A node started with a key already set has to fail a cas whose `from` doesn't match it and still read the preloaded
+    value.
+    */
+    #[test]
+    fn cas_sees_preloaded_state() -> anyhow::Result<()> {
+        let mut node: LinKvNode = test_harness::init("n0", &["n0"])?;
+        node = node.with_state(HashMap::from([(Value::Int(1), Value::Int(5))]));
+        let request = |msg_id, payload| {
+            Event::Message(Message {
+                src: NodeId::from("c0"),
+                dest: NodeId::from("n0"),
+                body: MessageBody {
+                    msg_id: Some(MsgId(msg_id)),
+                    in_reply_to: None,
+                    payload,
+                },
+            })
+        };
+        let replies = test_harness::drive(
+            &mut node,
+            vec![
+                request(
+                    0,
+                    KvPayload::Cas {
+                        key: 1.into(),
+                        from: 4.into(),
+                        to: 6.into(),
+                        create_if_not_exists: false,
+                    },
+                ),
+                request(1, KvPayload::Read { key: 1.into() }),
+            ],
+        )?;
+        let payloads: Vec<&KvPayload> = replies.iter().map(|reply| &reply.body.payload).collect();
+        match payloads.as_slice() {
+            [KvPayload::Error { code, .. }, KvPayload::ReadOk { value }]
+                if *code == error_code::PRECONDITION_FAILED && *value == 5 => {}
+            _ => bail!(
+                "cas 4 -> 6 and a read of a key preloaded as 5 got {:?}",
+                payloads
+            ),
+        }
+        Ok(())
+    }
+
+    /*
+    A write and a read sent to n0 for a key n1 owns have to go out to n1 as n0's own requests (the one relay hop), and
+    each client reply has to come back from n0, in reply to the client's msg_id, with the owner's payload.
+    */
+    #[test]
+    fn requests_are_relayed_to_the_owner() -> anyhow::Result<()> {
+        let mut cluster: Cluster<LinKvNode, KvPayload, InjectedPayload> = Cluster::new(2)?;
+        let (n0, n1) = (NodeId::from("n0"), NodeId::from("n1"));
+        let key = {
+            let node = cluster.node(&n0).context("n0 is missing")?;
+            (0..)
+                .map(Value::Int)
+                .find(|key| *node.owner_of(key) == n1)
+                .context("No key is owned by n1")?
+        };
+        let relay =
+            |cluster: &mut Cluster<_, _, _>, request: KvPayload| -> anyhow::Result<KvPayload> {
+                let msg_id = cluster.request(&n0, request)?;
+                let hop: Vec<_> = cluster.in_flight_messages().collect();
+                if !matches!(hop.as_slice(), [hop] if hop.src == n0 && hop.dest == n1) {
+                    bail!("n0 didn't forward to n1 as its own request: {:?}", hop);
+                }
+                cluster.deliver_all()?;
+                match cluster.take_client_replies().as_slice() {
+                    [reply] if reply.src == n0 && reply.body.in_reply_to == Some(msg_id) => {
+                        Ok(reply.body.payload.clone())
+                    }
+                    replies => bail!(
+                        "expected one reply from n0 to msg {}, got {:?}",
+                        msg_id,
+                        replies
+                    ),
+                }
+            };
+
+        let value = serde_json::to_value(7)?;
+        let key_json = serde_json::to_value(&key)?;
+        let write = relay(
+            &mut cluster,
+            KvPayload::Write {
+                key: key_json.clone(),
+                value: value.clone(),
+            },
+        )?;
+        let read = relay(&mut cluster, KvPayload::Read { key: key_json })?;
+        match (write, read) {
+            (KvPayload::WriteOk {}, KvPayload::ReadOk { value: read }) if read == value => {}
+            (write, read) => bail!("relayed write got {:?}, read got {:?}", write, read),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,10 @@
+// One module per challenge; each binary in src/bin (and the rustengan dispatcher) runs one of these nodes.
+// Every module defines its own payload enum (EchoPayload, BroadcastPayload, ...) carried in the shared, generic
+// Message<Payload>, so challenges that reuse a Maelstrom type name (e.g. read_ok) never collide.
+pub mod broadcast;
+pub mod counter;
+pub mod echo;
+pub mod kafka;
+pub mod lin_kv;
+pub mod txn;
+pub mod unique_id;
@@ -0,0 +1,536 @@
+use crate::txn::TxnOp;
+use crate::*;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::mpsc;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum TxnPayload {
+    Txn {
+        txn: Vec<TxnOp>,
+    },
+    TxnOk {
+        txn: Vec<TxnOp>,
+    },
+    // A committed write pushed to every peer; fire-and-forget, so there's no _ok
+    ReplicateWrite {
+        key: i64,
+        value: i64,
+        version: Version,
+    },
+}
+
+/* Events the node schedules for itself */
+#[derive(Debug, Clone)]
+pub enum InjectedPayload {
+    Commit(u64), // Validate and commit the pending transaction with this id (see PendingTxn)
+}
+
+// Logical timestamp a write is tagged with: (Lamport clock, node_index of the writer). Ordering the pair makes
+// concurrent writes to a key from different nodes resolve the same way everywhere
+type Version = (u64, usize);
+
+/*
+Isolation level transactions run at; set with the TXN_ISOLATION env var ("read-uncommitted", the default, or
+"read-committed").
+Under read-uncommitted each write lands in the store as soon as it's applied. Under read-committed a transaction's
+writes are buffered and only land in the store once the whole transaction has run, and its reads see the store as it
+was when the transaction started, plus its own earlier writes.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+}
+
+impl FromStr for IsolationLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "read-uncommitted" => Ok(IsolationLevel::ReadUncommitted),
+            "read-committed" => Ok(IsolationLevel::ReadCommitted),
+            _ => bail!(
+                "Unknown isolation level {:?}; expected read-uncommitted or read-committed",
+                s
+            ),
+        }
+    }
+}
+
+/*
+A read-committed transaction that has run against the store but not committed yet. With TXN_ABORT_ON_CONFLICT set
+the commit is a separate event, so other transactions and peers' writes can land in between; it only goes through if
+every key the transaction touched is still at the version it saw, and otherwise the client gets a txn-conflict error
+(code 30) and can retry. Without it transactions commit as they run and concurrent writes resolve last-write-wins.
+*/
+struct PendingTxn {
+    request: Message<TxnPayload>,
+    txn: Vec<TxnOp>, // With the values read filled in, for the txn_ok
+    writes: HashMap<i64, i64>,
+    snapshot: HashMap<i64, Option<Version>>, // Version of every key read or written, as the transaction saw it
+}
+
+/*
+Node in distributed system that handles totally-available transactions.
+Each node serves transactions from its own store and replicates committed writes to its peers asynchronously,
+resolving conflicting writes to a key by last-write-wins on their Version.
+*/
+pub struct TxnNode {
+    net: Network,
+    inject: mpsc::Sender<Event<TxnPayload, InjectedPayload>>,
+    isolation: IsolationLevel,
+    abort_on_conflict: bool,
+    store: HashMap<i64, (i64, Version)>,
+    clock: u64,
+    pending: HashMap<u64, PendingTxn>,
+    next_txn_id: u64,
+}
+
+impl TxnNode {
+    // A node running transactions at `isolation`, committing each as it runs
+    pub fn with_isolation(
+        net: Network,
+        inject: mpsc::Sender<Event<TxnPayload, InjectedPayload>>,
+        isolation: IsolationLevel,
+    ) -> Self {
+        TxnNode {
+            net,
+            inject,
+            isolation,
+            abort_on_conflict: false,
+            store: HashMap::new(),
+            clock: 0,
+            pending: HashMap::new(),
+            next_txn_id: 0,
+        }
+    }
+
+    fn read(&self, key: i64) -> Option<i64> {
+        self.store.get(&key).map(|(value, _)| *value)
+    }
+
+    fn version(&self, key: i64) -> Option<Version> {
+        self.store.get(&key).map(|(_, version)| *version)
+    }
+
+    // Writes a value from one of our own transactions, stamped with a version newer than any we've seen
+    fn write_local(&mut self, key: i64, value: i64) {
+        self.clock += 1;
+        let version = (self.clock, self.net.node_index());
+        self.store.insert(key, (value, version));
+    }
+
+    // Applies a peer's write unless we already hold a newer one for the key
+    fn write_replicated(&mut self, key: i64, value: i64, version: Version) {
+        self.clock = self.clock.max(version.0);
+        if self
+            .store
+            .get(&key)
+            .is_none_or(|(_, current)| *current < version)
+        {
+            self.store.insert(key, (value, version));
+        }
+    }
+
+    // Applies the ops in order; reads come back with the value they observed filled in
+    fn apply(&mut self, txn: &[TxnOp]) -> Vec<TxnOp> {
+        match self.isolation {
+            IsolationLevel::ReadUncommitted => txn
+                .iter()
+                .map(|op| match *op {
+                    TxnOp::Read { key, .. } => TxnOp::Read {
+                        key,
+                        value: self.read(key),
+                    },
+                    TxnOp::Write { key, value } => {
+                        self.write_local(key, value);
+                        *op
+                    }
+                })
+                .collect(),
+            IsolationLevel::ReadCommitted => {
+                let (txn, writes) = self.run_buffered(txn);
+                for (key, value) in writes {
+                    self.write_local(key, value);
+                }
+                txn
+            }
+        }
+    }
+
+    // Runs the ops with writes buffered rather than stored; returns the ops with reads filled in, and the writes
+    fn run_buffered(&self, txn: &[TxnOp]) -> (Vec<TxnOp>, HashMap<i64, i64>) {
+        // The store isn't touched until commit, so it doubles as the snapshot taken at txn start
+        let mut writes = HashMap::new();
+        let txn = txn
+            .iter()
+            .map(|op| match *op {
+                TxnOp::Read { key, .. } => TxnOp::Read {
+                    key,
+                    value: writes.get(&key).copied().or_else(|| self.read(key)),
+                },
+                TxnOp::Write { key, value } => {
+                    writes.insert(key, value);
+                    *op
+                }
+            })
+            .collect();
+        (txn, writes)
+    }
+
+    // Runs a read-committed transaction and schedules its commit for a later event rather than committing now
+    fn begin(&mut self, request: Message<TxnPayload>, txn: &[TxnOp]) {
+        let (txn, writes) = self.run_buffered(txn);
+        let snapshot = txn
+            .iter()
+            .map(|op| match op {
+                TxnOp::Read { key, .. } | TxnOp::Write { key, .. } => (*key, self.version(*key)),
+            })
+            .collect();
+        let id = self.next_txn_id;
+        self.next_txn_id += 1;
+        self.pending.insert(
+            id,
+            PendingTxn {
+                request,
+                txn,
+                writes,
+                snapshot,
+            },
+        );
+        // Only fails once the event loop is shutting down (or under test_harness, which scripts commits itself)
+        let _ = self
+            .inject
+            .send(Event::Injected(InjectedPayload::Commit(id)));
+    }
+
+    // Commits a pending transaction if nothing it touched has moved on since it ran, and aborts it otherwise
+    fn commit(&mut self, id: u64, output: &mut impl Write) -> anyhow::Result<()> {
+        let Some(pending) = self.pending.remove(&id) else {
+            return Ok(());
+        };
+        let conflict = pending
+            .snapshot
+            .iter()
+            .find(|(key, version)| self.version(**key) != **version);
+        if let Some((key, _)) = conflict {
+            return self.net.reply_error(
+                &pending.request,
+                error_code::TXN_CONFLICT,
+                format!("key {} was written by another transaction; retry", key),
+                output,
+            );
+        }
+        for (key, value) in pending.writes {
+            self.write_local(key, value);
+        }
+        self.replicate(&pending.txn, output)?;
+        self.net.reply(
+            &pending.request,
+            TxnPayload::TxnOk { txn: pending.txn },
+            output,
+        )
+    }
+
+    // Sends the final value of every key the transaction wrote to every peer, tagged with the version it was stored at
+    fn replicate(&self, txn: &[TxnOp], output: &mut impl Write) -> anyhow::Result<()> {
+        let written: HashSet<i64> = txn
+            .iter()
+            .filter_map(|op| match op {
+                TxnOp::Write { key, .. } => Some(*key),
+                TxnOp::Read { .. } => None,
+            })
+            .collect();
+        for key in written {
+            let (value, version) = self.store[&key];
+            for peer in self.net.peers() {
+                let payload = TxnPayload::ReplicateWrite {
+                    key,
+                    value,
+                    version,
+                };
+                self.net.send(peer.clone(), payload, output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Node<TxnPayload, InjectedPayload> for TxnNode {
+    fn from_init(
+        net: Network,
+        inject: mpsc::Sender<Event<TxnPayload, InjectedPayload>>,
+    ) -> anyhow::Result<Self> {
+        let isolation = match std::env::var("TXN_ISOLATION") {
+            Ok(isolation) => isolation.parse()?,
+            Err(_) => IsolationLevel::ReadUncommitted,
+        };
+        let mut node = TxnNode::with_isolation(net, inject, isolation);
+        node.abort_on_conflict = std::env::var_os("TXN_ABORT_ON_CONFLICT").is_some();
+        Ok(node)
+    }
+
+    fn step(
+        &mut self,
+        event: Event<TxnPayload, InjectedPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let input = match event {
+            Event::Message(input) => input,
+            Event::Injected(InjectedPayload::Commit(id)) => return self.commit(id, output),
+            Event::Eof => return Ok(()),
+        };
+        match &input.body.payload {
+            TxnPayload::Txn { txn }
+                if self.abort_on_conflict && self.isolation == IsolationLevel::ReadCommitted =>
+            {
+                self.begin(input.clone(), txn);
+            }
+            TxnPayload::Txn { txn } => {
+                let txn = self.apply(txn);
+                self.replicate(&txn, output)?;
+                self.net.reply(&input, TxnPayload::TxnOk { txn }, output)?;
+            }
+            TxnPayload::ReplicateWrite {
+                key,
+                value,
+                version,
+            } => {
+                self.write_replicated(*key, *value, *version);
+            }
+            TxnPayload::TxnOk { .. } => {
+                self.net.reject_unsupported(&input, output)?;
+            }
+        };
+
+        Ok(())
+    }
+}
+
+/*
+A TxnNode pinned to read-uncommitted whatever TXN_ISOLATION says, for the single-node read-uncommitted grade: every
+write lands in the store the moment it's applied.
+*/
+pub struct ReadUncommittedTxnNode(TxnNode);
+
+impl Node<TxnPayload, InjectedPayload> for ReadUncommittedTxnNode {
+    fn from_init(
+        net: Network,
+        inject: mpsc::Sender<Event<TxnPayload, InjectedPayload>>,
+    ) -> anyhow::Result<Self> {
+        Ok(ReadUncommittedTxnNode(TxnNode::with_isolation(
+            net,
+            inject,
+            IsolationLevel::ReadUncommitted,
+        )))
+    }
+
+    fn step(
+        &mut self,
+        event: Event<TxnPayload, InjectedPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        self.0.step(event, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::Cluster;
+
+    /*
+    With TXN_ABORT_ON_CONFLICT on, two read-committed transactions that both read and write key 1 run before either
+    commits. Exactly one has to commit and the other has to get a retriable txn-conflict error, and a transaction run
+    afterwards has to read the winner's write.
+    */
+    #[test]
+    fn conflicting_transactions_commit_one() -> anyhow::Result<()> {
+        let mut node: TxnNode = test_harness::init("n0", &["n0"])?;
+        node.isolation = IsolationLevel::ReadCommitted;
+        node.abort_on_conflict = true;
+        let txn = |msg_id, txn: Vec<TxnOp>| {
+            Event::Message(Message {
+                src: NodeId::from("c1"),
+                dest: NodeId::from("n0"),
+                body: MessageBody {
+                    msg_id: Some(MsgId(msg_id)),
+                    in_reply_to: None,
+                    payload: TxnPayload::Txn { txn },
+                },
+            })
+        };
+        let read_then_write = |value| {
+            vec![
+                TxnOp::Read {
+                    key: 1,
+                    value: None,
+                },
+                TxnOp::Write { key: 1, value },
+            ]
+        };
+        // test_harness drops what the node injects, so the commits it would have scheduled are scripted in their place
+        let events = vec![
+            txn(1, read_then_write(10)),
+            txn(2, read_then_write(20)),
+            Event::Injected(InjectedPayload::Commit(0)),
+            Event::Injected(InjectedPayload::Commit(1)),
+            txn(
+                3,
+                vec![TxnOp::Read {
+                    key: 1,
+                    value: None,
+                }],
+            ),
+            Event::Injected(InjectedPayload::Commit(2)),
+        ];
+        let mut output = Vec::new();
+        for event in events {
+            step_node(&mut node, event, &mut output)?;
+        }
+        let replies: Vec<Message<serde_json::Value>> =
+            serde_json::Deserializer::from_slice(&output)
+                .into_iter()
+                .collect::<Result<_, _>>()?;
+        let bodies: Vec<(Option<MsgId>, &serde_json::Value)> = replies
+            .iter()
+            .map(|reply| (reply.body.in_reply_to, &reply.body.payload))
+            .collect();
+        let expected_read = serde_json::json!([["r", 1, 10]]);
+        let one_committed = matches!(
+            bodies.as_slice(),
+            [(Some(MsgId(1)), won), (Some(MsgId(2)), lost), (Some(MsgId(3)), after)]
+                if won["type"] == "txn_ok"
+                    && lost["type"] == "error"
+                    && lost["code"] == error_code::TXN_CONFLICT
+                    && after["txn"] == expected_read
+        );
+        assert!(
+            one_committed,
+            "two conflicting transactions and a read got {:?}",
+            bodies
+        );
+        Ok(())
+    }
+
+    fn request(msg_id: usize, txn: Vec<TxnOp>) -> Event<TxnPayload, InjectedPayload> {
+        Event::Message(Message {
+            src: NodeId::from("c1"),
+            dest: NodeId::from("n0"),
+            body: MessageBody {
+                msg_id: Some(MsgId(msg_id)),
+                in_reply_to: None,
+                payload: TxnPayload::Txn { txn },
+            },
+        })
+    }
+
+    fn read(key: i64) -> TxnOp {
+        TxnOp::Read { key, value: None }
+    }
+
+    fn read_back(key: i64, value: Option<i64>) -> TxnOp {
+        TxnOp::Read { key, value }
+    }
+
+    // The ops of every txn_ok among `replies`, in order
+    fn answered(replies: Vec<Message<TxnPayload>>) -> Vec<Vec<TxnOp>> {
+        replies
+            .into_iter()
+            .filter_map(|reply| match reply.body.payload {
+                TxnPayload::TxnOk { txn } => Some(txn),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Under read-committed a transaction reads the store as it started, except for keys it has itself written since
+    #[test]
+    fn read_committed_reads_its_own_writes() -> anyhow::Result<()> {
+        let mut node: TxnNode = test_harness::init("n0", &["n0"])?;
+        node.isolation = IsolationLevel::ReadCommitted;
+        let events = vec![
+            request(1, vec![TxnOp::Write { key: 1, value: 5 }]),
+            request(
+                2,
+                vec![read(1), TxnOp::Write { key: 1, value: 6 }, read(1), read(2)],
+            ),
+        ];
+        let replies = answered(test_harness::drive(&mut node, events)?);
+        let expected = vec![
+            read_back(1, Some(5)),
+            TxnOp::Write { key: 1, value: 6 },
+            read_back(1, Some(6)),
+            read_back(2, None),
+        ];
+        assert_eq!(replies.get(1), Some(&expected));
+        Ok(())
+    }
+
+    /*
+    A read-committed transaction's writes have to stay invisible to other transactions until it commits: run with
+    commits as separate events, a read between a write and its commit sees the old value and one after sees the new.
+    */
+    #[test]
+    fn uncommitted_writes_stay_hidden() -> anyhow::Result<()> {
+        let mut node: TxnNode = test_harness::init("n0", &["n0"])?;
+        node.isolation = IsolationLevel::ReadCommitted;
+        node.abort_on_conflict = true;
+        let events = vec![
+            request(1, vec![TxnOp::Write { key: 1, value: 5 }]),
+            request(2, vec![read(1)]),
+            // The read commits first, since committing the write under it would make it conflict
+            Event::Injected(InjectedPayload::Commit(1)),
+            Event::Injected(InjectedPayload::Commit(0)),
+            request(3, vec![read(1)]),
+            Event::Injected(InjectedPayload::Commit(2)),
+        ];
+        let replies = answered(test_harness::drive(&mut node, events)?);
+        let expected = vec![
+            vec![read_back(1, None)],
+            vec![TxnOp::Write { key: 1, value: 5 }],
+            vec![read_back(1, Some(5))],
+        ];
+        assert_eq!(replies, expected);
+        Ok(())
+    }
+
+    /*
+    Two nodes each write key 1 before hearing of the other's write. Once the replicated writes are delivered both
+    have to read the same value, the one with the newer version, and a peer's older write must not overwrite it.
+    */
+    #[test]
+    fn concurrent_writes_converge() -> anyhow::Result<()> {
+        let mut cluster: Cluster<TxnNode, TxnPayload, InjectedPayload> = Cluster::new(2)?;
+        let [n0, n1] = [NodeId::from("n0"), NodeId::from("n1")];
+        cluster.request(
+            &n0,
+            TxnPayload::Txn {
+                txn: vec![TxnOp::Write { key: 1, value: 10 }],
+            },
+        )?;
+        cluster.request(
+            &n1,
+            TxnPayload::Txn {
+                txn: vec![TxnOp::Write { key: 1, value: 20 }],
+            },
+        )?;
+        cluster.deliver_all()?;
+        cluster.take_client_replies();
+
+        for node in [&n0, &n1] {
+            cluster.request(node, TxnPayload::Txn { txn: vec![read(1)] })?;
+        }
+        let reads = answered(cluster.take_client_replies());
+        // Both writes have clock 1, so n1's, with the higher node index, is the newer version
+        assert_eq!(reads, vec![vec![read_back(1, Some(20))]; 2]);
+        Ok(())
+    }
+}
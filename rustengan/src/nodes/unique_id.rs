@@ -0,0 +1,163 @@
+use crate::clock::{Clock, SystemClock};
+use crate::*;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::mpsc;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum UniqueIDPayload {
+    Generate {},
+    GenerateOk { id: String },
+}
+
+// Snowflake layout: 42 bits of ms timestamp | NODE_BITS of node index | SEQUENCE_BITS of per-ms sequence
+const TIMESTAMP_BITS: u32 = 42;
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 64 - TIMESTAMP_BITS - NODE_BITS;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+pub struct UniqueIDNode<C: Clock = SystemClock> {
+    // Node in distributed system that handles unique ID generation
+    net: Network,
+    clock: C,
+    last_ts: u64,
+    sequence: u64,
+}
+
+impl<C: Clock> UniqueIDNode<C> {
+    // A node that stamps its ids with `clock`'s time, e.g. a MockClock a test can move
+    pub fn with_clock(net: Network, clock: C) -> anyhow::Result<Self> {
+        if net.node_index() >= 1 << NODE_BITS {
+            bail!("Too many nodes to fit a node index into {} bits", NODE_BITS);
+        }
+        Ok(UniqueIDNode {
+            net,
+            clock,
+            last_ts: 0,
+            sequence: 0,
+        })
+    }
+
+    fn gen_unique_id(&mut self) -> String {
+        /*
+        ID is a 64-bit Snowflake-style integer consisting of:
+        1. Unix timestamp in milliseconds (42 bits, good until ~2109)
+        2. Index of the node it's generated on within the sorted node_ids (NODE_BITS)
+        3. Sequence number that resets every millisecond (the remaining bits)
+        This ensures IDs are unique across nodes (distinct node bits) and within a node (distinct
+        (timestamp, sequence) pairs), as well as roughly sortable by generation time.
+
+        NOTE: Each node can generate at most 2^SEQUENCE_BITS IDs per millisecond.
+        Once the sequence is exhausted we move on to the next millisecond ahead of the clock rather than wait for it.
+        The timestamp never goes below the last one we used, so if the wall clock steps backwards (NTP adjustments,
+        VM migrations) we keep counting up the sequence from there instead of reissuing old IDs.
+        */
+        let curr_ts = self.clock.now_millis().max(self.last_ts);
+        if curr_ts == self.last_ts {
+            if self.sequence == MAX_SEQUENCE {
+                self.last_ts += 1;
+                self.sequence = 0;
+            } else {
+                self.sequence += 1;
+            }
+        } else {
+            self.last_ts = curr_ts;
+            self.sequence = 0;
+        }
+
+        let id = (self.last_ts << (NODE_BITS + SEQUENCE_BITS))
+            | ((self.net.node_index() as u64) << SEQUENCE_BITS)
+            | self.sequence;
+        id.to_string()
+    }
+}
+
+impl<C: Clock + Default> Node<UniqueIDPayload> for UniqueIDNode<C> {
+    fn from_init(
+        net: Network,
+        _inject: mpsc::Sender<Event<UniqueIDPayload>>,
+    ) -> anyhow::Result<Self> {
+        UniqueIDNode::with_clock(net, C::default())
+    }
+
+    fn step(
+        &mut self,
+        event: Event<UniqueIDPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Event::Message(input) = event else {
+            return Ok(());
+        };
+        match &input.body.payload {
+            UniqueIDPayload::Generate { .. } => {
+                let unique_id = self.gen_unique_id();
+                let payload = UniqueIDPayload::GenerateOk { id: unique_id };
+                self.net.reply(&input, payload, output)?;
+            }
+            UniqueIDPayload::GenerateOk { .. } => {
+                self.net.reject_unsupported(&input, output)?;
+            }
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::collections::HashSet;
+
+    fn node(clock: &MockClock) -> anyhow::Result<UniqueIDNode<MockClock>> {
+        let init = Init {
+            node_id: NodeId::from("n1"),
+            node_ids: vec![NodeId::from("n0"), NodeId::from("n1")],
+        };
+        UniqueIDNode::with_clock(Network::new(init)?, clock.clone())
+    }
+
+    // Ids generated after the clock steps back behind ones already issued must not repeat any of them
+    #[test]
+    fn clock_stepping_back_repeats_no_id() -> anyhow::Result<()> {
+        let clock = MockClock::new(1_000);
+        let mut node = node(&clock)?;
+        let mut ids = HashSet::new();
+        for now in [1_000, 1_005, 1_001, 900, 1_005, 1_006] {
+            clock.set(now);
+            for _ in 0..3 {
+                let id = node.gen_unique_id();
+                assert!(
+                    ids.insert(id.clone()),
+                    "{} issued twice (clock at {})",
+                    id,
+                    now
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Running out of sequence numbers within one millisecond has to move on to the next one, not wrap around
+    #[test]
+    fn sequence_overflow_repeats_no_id() -> anyhow::Result<()> {
+        let clock = MockClock::new(1_000);
+        let mut node = node(&clock)?;
+        let ids: Vec<u64> = (0..2 * (MAX_SEQUENCE + 1) + 1)
+            .map(|_| node.gen_unique_id().parse())
+            .collect::<Result<_, _>>()?;
+        assert!(
+            ids.windows(2).all(|pair| pair[0] < pair[1]),
+            "ids did not keep increasing"
+        );
+        // Time catching up with the ids already issued ahead of it must not reissue them either
+        clock.set(1_002);
+        let next: u64 = node.gen_unique_id().parse()?;
+        assert!(next > ids[ids.len() - 1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+use crate::clock::{Clock, SystemClock};
+
+use anyhow::{bail, Context};
+
+/*
+Token bucket: holds up to `capacity` tokens, refilled continuously at `per_second`, and each message sent takes one.
+Over any window of T seconds at most capacity + per_second * T messages get through; whoever is refused defers the
+message to a later window rather than dropping it.
+*/
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    refilled_at: u64, // Clock millis of the last refill
+    clock: Box<dyn Clock + Send>,
+}
+
+impl std::fmt::Debug for TokenBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenBucket")
+            .field("capacity", &self.capacity)
+            .field("per_second", &self.per_second)
+            .field("tokens", &self.tokens)
+            .finish()
+    }
+}
+
+impl TokenBucket {
+    // Starts full, so a burst of up to `capacity` goes out straight away
+    pub fn new(capacity: u32, per_second: u32) -> Self {
+        TokenBucket::with_clock(capacity, per_second, SystemClock)
+    }
+
+    pub fn with_clock(capacity: u32, per_second: u32, clock: impl Clock + Send + 'static) -> Self {
+        TokenBucket {
+            capacity: capacity as f64,
+            per_second: per_second as f64,
+            tokens: capacity as f64,
+            refilled_at: clock.now_millis(),
+            clock: Box::new(clock),
+        }
+    }
+
+    /*
+    Reads a rate from `rate_var` (messages per second) and a capacity from `burst_var` (defaulting to one second's
+    worth). No bucket, and so no limit, if `rate_var` is unset.
+    */
+    pub fn from_env(rate_var: &str, burst_var: &str) -> anyhow::Result<Option<Self>> {
+        let Ok(rate) = std::env::var(rate_var) else {
+            return Ok(None);
+        };
+        let per_second: u32 = rate
+            .parse()
+            .with_context(|| format!("Invalid {}: {}", rate_var, rate))?;
+        let capacity = match std::env::var(burst_var) {
+            Ok(burst) => burst
+                .parse()
+                .with_context(|| format!("Invalid {}: {}", burst_var, burst))?,
+            Err(_) => per_second,
+        };
+        if per_second == 0 || capacity == 0 {
+            bail!("{} and {} must be positive", rate_var, burst_var);
+        }
+        Ok(Some(TokenBucket::new(capacity, per_second)))
+    }
+
+    // Takes a token if there is one; false means the caller should hold off
+    pub fn try_take(&mut self) -> bool {
+        let now = self.clock.now_millis();
+        // A clock that moved backwards refills nothing rather than panicking
+        let elapsed = now.saturating_sub(self.refilled_at) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.refilled_at = self.refilled_at.max(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{error_code, ErrorPayload, Message, MessageBody};
+
+use anyhow::{bail, Context};
+use serde_json::Deserializer;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+/*
+What to do with messages that arrive before Init; set with the PRE_INIT_POLICY env var ("buffer", the default, or
+"reject"). Maelstrom always sends Init first, but a racy or hand-written driver may not, and no node can do anything
+sensible before it knows its id and peers. Buffered messages are handed to the node, in the order they came, as soon
+as Init has been answered; rejected ones get a temporarily-unavailable error (code 11) so the sender can retry.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreInitPolicy {
+    Buffer,
+    Reject,
+}
+
+impl FromStr for PreInitPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "buffer" => Ok(PreInitPolicy::Buffer),
+            "reject" => Ok(PreInitPolicy::Reject),
+            _ => bail!("Unknown PRE_INIT_POLICY {:?}; expected buffer or reject", s),
+        }
+    }
+}
+
+impl PreInitPolicy {
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("PRE_INIT_POLICY") {
+            Ok(policy) => policy.parse(),
+            Err(_) => Ok(PreInitPolicy::Buffer),
+        }
+    }
+}
+
+/*
+Reads up to and including the Init message, dealing with whatever comes before it by `policy` (rejections are
+written to `output`). Returns Init and the input to put back in front of the rest: the buffered messages, one per
+line, then anything that shared Init's line after it.
+*/
+pub fn read_init(
+    input: &mut impl BufRead,
+    policy: PreInitPolicy,
+    output: &mut impl Write,
+) -> anyhow::Result<(serde_json::Value, Vec<u8>)> {
+    let mut buffered = Vec::new();
+    loop {
+        let mut line = String::new();
+        if input
+            .read_line(&mut line)
+            .context("Failed to read Init message")?
+            == 0
+        {
+            bail!("No Init message received from Maelstrom!");
+        }
+        let mut stream = Deserializer::from_str(&line).into_iter::<serde_json::Value>();
+        while let Some(message) = stream.next() {
+            let message = message.context("Message before Init could not be deserialized!")?;
+            if message["body"]["type"] == "init" {
+                buffered.extend_from_slice(&line.as_bytes()[stream.byte_offset()..]);
+                return Ok((message, buffered));
+            }
+            match policy {
+                PreInitPolicy::Buffer => {
+                    serde_json::to_writer(&mut buffered, &message)?;
+                    buffered.push(b'\n');
+                }
+                PreInitPolicy::Reject => reject(message, output)?,
+            }
+        }
+    }
+}
+
+fn reject(message: serde_json::Value, output: &mut impl Write) -> anyhow::Result<()> {
+    eprintln!("Rejecting a message that came before Init: {}", message);
+    let Ok(message) = serde_json::from_value::<Message<serde_json::Value>>(message) else {
+        return Ok(());
+    };
+    let Some(msg_id) = message.body.msg_id else {
+        return Ok(());
+    };
+    let reply = Message {
+        src: message.dest,
+        dest: message.src,
+        body: MessageBody {
+            msg_id: None,
+            in_reply_to: Some(msg_id),
+            payload: ErrorPayload::Error {
+                code: error_code::TEMPORARILY_UNAVAILABLE,
+                text: "not initialized yet".to_string(),
+            },
+        },
+    };
+    reply.send(output)?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MsgId, NodeId};
+
+    const INPUT: &str = concat!(
+        r#"{"src":"c1","dest":"n0","body":{"type":"read","msg_id":1}}"#,
+        "\n",
+        r#"{"src":"c1","dest":"n0","body":{"type":"broadcast","message":5,"msg_id":2}}"#,
+        "\n",
+        r#"{"src":"c0","dest":"n0","body":{"type":"init","node_id":"n0","node_ids":["n0"],"msg_id":3}}"#,
+        r#"{"src":"c1","dest":"n0","body":{"type":"read","msg_id":4}}"#,
+        "\n",
+    );
+
+    fn msg_ids(bytes: &[u8]) -> anyhow::Result<Vec<(Option<MsgId>, Option<MsgId>)>> {
+        Deserializer::from_slice(bytes)
+            .into_iter::<Message<serde_json::Value>>()
+            .map(|message| {
+                let message = message?;
+                Ok((message.body.msg_id, message.body.in_reply_to))
+            })
+            .collect()
+    }
+
+    /*
+    A read and a broadcast sent before Init, then a read that shares Init's line: buffering has to put the early two
+    back ahead of the late one, in order, answering nothing.
+    */
+    #[test]
+    fn buffered_messages_are_replayed_in_order() -> anyhow::Result<()> {
+        let mut output = Vec::new();
+        let (init, rest) = read_init(&mut INPUT.as_bytes(), PreInitPolicy::Buffer, &mut output)?;
+        assert_eq!(init["body"]["msg_id"], 3);
+        assert!(
+            output.is_empty(),
+            "answered {:?}",
+            String::from_utf8_lossy(&output)
+        );
+        let expected = [1, 2, 4].map(|id| (Some(MsgId(id)), None)).to_vec();
+        assert_eq!(msg_ids(&rest)?, expected);
+        Ok(())
+    }
+
+    // Rejecting has to answer each early message with a code 11 error from the address it was sent to, leaving only
+    // the late read
+    #[test]
+    fn rejected_messages_get_temporarily_unavailable() -> anyhow::Result<()> {
+        let mut output = Vec::new();
+        let (_, rest) = read_init(&mut INPUT.as_bytes(), PreInitPolicy::Reject, &mut output)?;
+        let rejections: Vec<Message<serde_json::Value>> = Deserializer::from_slice(&output)
+            .into_iter()
+            .collect::<Result<_, _>>()?;
+        let rejected_in_order = rejections.len() == 2
+            && rejections.iter().zip([1, 2]).all(|(reply, id)| {
+                reply.src == NodeId::from("n0")
+                    && reply.body.in_reply_to == Some(MsgId(id))
+                    && reply.body.payload["code"] == error_code::TEMPORARILY_UNAVAILABLE
+            });
+        assert!(rejected_in_order, "answered {:?}", rejections);
+        assert_eq!(msg_ids(&rest)?, [(Some(MsgId(4)), None)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{KeyHash, Network, NodeId};
+
+use anyhow::{bail, Context};
+use std::collections::BTreeMap;
+
+// Default for the VIRTUAL_NODES env var
+const VIRTUAL_NODES: usize = 64;
+
+/*
+Consistent-hash ring for picking a key's owner. Every node is hashed onto the ring at `virtual_nodes` points
+("<node>#<i>"), and a key belongs to the node at the first point at or after the key's own hash, wrapping around.
+Adding a node only takes over the keys just before its points, about 1/(N+1) of them, and removing one only hands
+its keys to the nodes after it; with hash % N, changing N moves nearly every key. More virtual nodes spread keys more
+evenly at the cost of a bigger ring. Every node has to build its ring from the same members, points and hash.
+*/
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    hash: KeyHash,
+    points: BTreeMap<u64, NodeId>,
+}
+
+impl HashRing {
+    pub fn new(virtual_nodes: usize, hash: KeyHash) -> Self {
+        HashRing {
+            virtual_nodes: virtual_nodes.max(1),
+            hash,
+            points: BTreeMap::new(),
+        }
+    }
+
+    // Every node in `net`'s cluster, with net's key hash and VIRTUAL_NODES points each
+    pub fn for_cluster(net: &Network) -> anyhow::Result<Self> {
+        let virtual_nodes = match std::env::var("VIRTUAL_NODES") {
+            Ok(count) => count
+                .parse()
+                .with_context(|| format!("Invalid VIRTUAL_NODES: {}", count))?,
+            Err(_) => VIRTUAL_NODES,
+        };
+        if virtual_nodes == 0 {
+            bail!("VIRTUAL_NODES must be positive");
+        }
+        let mut ring = HashRing::new(virtual_nodes, net.key_hash);
+        for node in &net.node_ids {
+            ring.add_node(node.clone());
+        }
+        Ok(ring)
+    }
+
+    // Where `text` lands on the ring. FNV-1a leaves near-identical strings like "n3#1" and "n3#2" close together, so
+    // the hash goes through murmur3's finalizer to scatter them around the whole ring
+    fn position(&self, text: &str) -> u64 {
+        let mut hash = (self.hash)(text);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^ (hash >> 33)
+    }
+
+    fn point(&self, node: &NodeId, i: usize) -> u64 {
+        self.position(&format!("{}#{}", node, i))
+    }
+
+    pub fn add_node(&mut self, node: NodeId) {
+        for i in 0..self.virtual_nodes {
+            // On the rare point two nodes share, the smaller id wins, so the outcome doesn't depend on insertion order
+            let point = self.point(&node, i);
+            let owner = self.points.entry(point).or_insert_with(|| node.clone());
+            if node < *owner {
+                *owner = node.clone();
+            }
+        }
+    }
+
+    pub fn remove_node(&mut self, node: &NodeId) {
+        self.points.retain(|_, owner| owner != node);
+    }
+
+    // None only if the ring is empty
+    pub fn owner(&self, key: &str) -> Option<&NodeId> {
+        self.successors(key).next()
+    }
+
+    // Every node on the ring, each once, in the order met walking clockwise from `key`: its owner, then who'd take over
+    pub fn successors(&self, key: &str) -> impl Iterator<Item = &NodeId> {
+        let hash = self.position(key);
+        let mut seen: Vec<&NodeId> = Vec::new();
+        self.points
+            .range(hash..)
+            .chain(self.points.range(..hash))
+            .map(|(_, node)| node)
+            .filter(move |node| {
+                let first = !seen.contains(node);
+                if first {
+                    seen.push(node);
+                }
+                first
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+    Adding an 11th node to a 10-node ring has to move roughly 1/11 of 10000 keys (between half and double that), every
+    one of them to the new node, and removing it again has to put every key back where it was.
+    */
+    #[test]
+    fn adding_a_node_moves_only_its_share() {
+        const NODES: usize = 10;
+        const KEYS: usize = 10_000;
+        let mut ring = HashRing::new(VIRTUAL_NODES, crate::stable_hash);
+        for i in 0..NODES {
+            ring.add_node(NodeId(format!("n{}", i)));
+        }
+        let keys: Vec<String> = (0..KEYS).map(|i| format!("key-{}", i)).collect();
+        let owners = |ring: &HashRing| -> Vec<NodeId> {
+            keys.iter()
+                .map(|key| ring.owner(key).cloned().unwrap_or(NodeId::from("")))
+                .collect()
+        };
+        let before = owners(&ring);
+
+        let added = NodeId(format!("n{}", NODES));
+        ring.add_node(added.clone());
+        let after = owners(&ring);
+        let moved: Vec<&NodeId> = before
+            .iter()
+            .zip(&after)
+            .filter(|(before, after)| before != after)
+            .map(|(_, after)| after)
+            .collect();
+        let fair_share = KEYS / (NODES + 1);
+        assert!(
+            (fair_share / 2..=fair_share * 2).contains(&moved.len()),
+            "adding a node to {} moved {} of {} keys, expected about {}",
+            NODES,
+            moved.len(),
+            KEYS,
+            fair_share
+        );
+        assert!(
+            moved.iter().all(|owner| **owner == added),
+            "adding {} moved keys elsewhere too",
+            added
+        );
+
+        ring.remove_node(&added);
+        assert!(
+            owners(&ring) == before,
+            "removing the added node didn't restore every key's owner"
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::Context;
+use std::fmt::Debug;
+
+/*
+Source of randomness for every randomized decision a node makes (gossip fan-out sampling, backoff jitter, CAS retry
+pauses). They all draw from the one Rng on the node's Network, seeded from RNG_SEED, so fixing the seed makes a run
+repeat the same choices; without it the seed comes from the clock.
+*/
+pub trait Rng: Debug {
+    fn next_u64(&mut self) -> u64;
+}
+
+/* xorshift64: tiny and fast, and plenty for spreading out retries and picking peers */
+#[derive(Debug, Clone)]
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    // Any seed works, 0 included; it's spread over every bit first so nearby seeds (even 2k and 2k+1) still differ
+    pub fn new(seed: u64) -> Self {
+        XorShift {
+            state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+        }
+    }
+
+    /*
+    Seeded from RNG_SEED or else the clock, mixed with `stream` (e.g. the node index) so nodes sharing a seed still
+    don't make the same choices in lockstep.
+    */
+    pub fn from_env(stream: u64) -> anyhow::Result<Self> {
+        let seed = match std::env::var("RNG_SEED") {
+            Ok(seed) => seed
+                .parse()
+                .with_context(|| format!("RNG_SEED {} isn't a number", seed))?,
+            Err(_) => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64),
+        };
+        Ok(XorShift::new(
+            seed ^ (stream + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15),
+        ))
+    }
+}
+
+impl Rng for XorShift {
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Seeds one apart (including the pairs that differ only in the lowest bit) have to give different streams
+    #[test]
+    fn neighbouring_seeds_differ() {
+        for seed in 0..64 {
+            let [mut a, mut b] = [seed, seed + 1].map(XorShift::new);
+            let (a, b): (Vec<_>, Vec<_>) = (0..4).map(|_| (a.next_u64(), b.next_u64())).unzip();
+            assert_ne!(a, b, "seeds {} and {} gave the same stream", seed, seed + 1);
+        }
+    }
+}
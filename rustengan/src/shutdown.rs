@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/*
+Graceful shutdown on SIGTERM/SIGINT. Maelstrom stops nodes with a signal, and by default that kills the process with
+whatever the stdout writer is still holding (a coalescing window can be sitting on many replies). Once install has
+run, the signal only sets a flag; run_node notices it, finishes the message it's on, and shuts down the same way it
+does at EOF, so the writer gets out every queued line (always whole lines) and the process exits 0.
+No signal crate is vendored, so this talks to libc, which std already links, directly.
+*/
+
+// A signal handler can't safely do anything but store to an atomic
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+mod sys {
+    use std::os::raw::c_int;
+
+    pub const SIGINT: c_int = 2;
+    pub const SIGTERM: c_int = 15;
+
+    extern "C" {
+        // The return value is the previous handler, which we never restore
+        pub fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+        pub fn kill(pid: c_int, signum: c_int) -> c_int;
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: std::os::raw::c_int) {
+    SIGNALLED.store(true, Ordering::SeqCst);
+}
+
+// Replaces the default die-on-the-spot handling of SIGTERM and SIGINT with setting the flag
+pub fn install() {
+    #[cfg(unix)]
+    unsafe {
+        sys::signal(sys::SIGINT, on_signal);
+        sys::signal(sys::SIGTERM, on_signal);
+    }
+}
+
+pub fn signalled() -> bool {
+    SIGNALLED.load(Ordering::SeqCst)
+}
+
+// Sends SIGTERM to another process, the way Maelstrom stops a node; for the tests
+pub fn terminate(pid: u32) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    if unsafe { sys::kill(pid as std::os::raw::c_int, sys::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    #[cfg(not(unix))]
+    anyhow::bail!("Can't signal process {}: signals are unix-only", pid);
+    #[cfg(unix)]
+    Ok(())
+}
@@ -0,0 +1,475 @@
+use crate::kv::KvPayload;
+use crate::nodes::lin_kv::{self, LinKvNode};
+use crate::{
+    shutdown, test_harness, Event, Init, InitPayload, Message, MessageBody, MsgId, Network, Node,
+    NodeId,
+};
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Write};
+use std::marker::PhantomData;
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/*
+In-process cluster for exercising a node type without Maelstrom. Nodes are driven with test_harness::drive; whatever
+one sends to another is queued in a single FIFO until the driver delivers or drops it, and replies to clients are
+collected for the driver to inspect. Nothing runs on its own: ticks, deliveries and partitions all happen only when
+the driver asks, so a scenario plays out the same way every time (apart from anything a node times by the clock).
+*/
+pub struct Cluster<N, Payload, InjectedPayload> {
+    node_ids: Vec<NodeId>,
+    nodes: HashMap<NodeId, N>,
+    in_flight: VecDeque<Message<Payload>>,
+    // Which side of the current partition each node is on; nodes on different sides can't reach each other
+    sides: HashMap<NodeId, usize>,
+    client_replies: Vec<Message<Payload>>,
+    next_client_msg_id: usize,
+    injected: PhantomData<InjectedPayload>,
+}
+
+// Clients never sit behind a partition, matching Maelstrom, which only cuts links between nodes
+const CLIENT: &str = "c0";
+// The kv services a Process answers; both are served by the same in-process store
+const KV_SERVICES: [&str; 2] = ["lin-kv", "seq-kv"];
+// Network::new leaves services out of a node's cluster, so the in-process store runs under a node id of its own
+const KV_STAND_IN: &str = "kv0";
+
+impl<N, Payload, InjectedPayload> Cluster<N, Payload, InjectedPayload>
+where
+    N: Node<Payload, InjectedPayload>,
+    Payload: serde::de::DeserializeOwned,
+    InjectedPayload: Clone,
+{
+    // Builds nodes n0..n{node_count - 1}, each initialized as if Maelstrom had sent it Init
+    pub fn new(node_count: usize) -> anyhow::Result<Self> {
+        Cluster::with_nodes(node_count, N::from_init)
+    }
+
+    /*
+    Like new, but each node is built by `build` from its Network and inject channel instead of by from_init, e.g. to
+    hand it a config or a seeded rng. As with test_harness::init, whatever a node injects is discarded.
+    */
+    pub fn with_nodes(
+        node_count: usize,
+        mut build: impl FnMut(
+            Network,
+            mpsc::Sender<Event<Payload, InjectedPayload>>,
+        ) -> anyhow::Result<N>,
+    ) -> anyhow::Result<Self> {
+        let node_ids: Vec<NodeId> = (0..node_count).map(|i| NodeId(format!("n{}", i))).collect();
+        let mut nodes = HashMap::new();
+        for id in &node_ids {
+            let init = Init {
+                node_id: id.clone(),
+                node_ids: node_ids.clone(),
+            };
+            let (inject, _) = mpsc::channel();
+            nodes.insert(id.clone(), build(Network::new(init)?, inject)?);
+        }
+        Ok(Cluster {
+            node_ids,
+            nodes,
+            in_flight: VecDeque::new(),
+            sides: HashMap::new(),
+            client_replies: Vec::new(),
+            next_client_msg_id: 0,
+            injected: PhantomData,
+        })
+    }
+
+    pub fn node_ids(&self) -> &[NodeId] {
+        &self.node_ids
+    }
+
+    // For checking a node's internal state once a scenario has played out
+    pub fn node(&self, id: &NodeId) -> Option<&N> {
+        self.nodes.get(id)
+    }
+
+    // For setting a node up before a scenario starts, e.g. handing it a clock the driver controls
+    pub fn node_mut(&mut self, id: &NodeId) -> Option<&mut N> {
+        self.nodes.get_mut(id)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    // Messages sent but not yet delivered or dropped, oldest first
+    pub fn in_flight_messages(&self) -> impl Iterator<Item = &Message<Payload>> {
+        self.in_flight.iter()
+    }
+
+    // Sends a client request to `node`; it's handled right away, and anything it sends is queued. Returns the request's
+    // msg_id, which the reply's in_reply_to should match.
+    pub fn request(&mut self, node: &NodeId, payload: Payload) -> anyhow::Result<MsgId> {
+        let msg_id = self.next_client_msg_id;
+        self.next_client_msg_id += 1;
+        let message = Message {
+            src: NodeId::from(CLIENT),
+            dest: node.clone(),
+            body: MessageBody {
+                msg_id: Some(MsgId(msg_id)),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        self.step(node.as_str(), Event::Message(message))?;
+        Ok(MsgId(msg_id))
+    }
+
+    // Hands every node the same injected event (e.g. a gossip tick), in node order
+    pub fn tick(&mut self, payload: InjectedPayload) -> anyhow::Result<()> {
+        for node in self.node_ids.clone() {
+            self.step(node.as_str(), Event::Injected(payload.clone()))?;
+        }
+        Ok(())
+    }
+
+    // Delivers the oldest queued message, or drops it if its sender and receiver are partitioned.
+    // Returns false once nothing is left to deliver.
+    pub fn deliver_next(&mut self) -> anyhow::Result<bool> {
+        let Some(message) = self.in_flight.pop_front() else {
+            return Ok(false);
+        };
+        if self.connected(message.src.as_str(), message.dest.as_str()) {
+            let dest = message.dest.clone();
+            self.step(dest.as_str(), Event::Message(message))?;
+        }
+        Ok(true)
+    }
+
+    // Delivers (or drops) messages until none are queued, including any sent in response
+    pub fn deliver_all(&mut self) -> anyhow::Result<()> {
+        while self.deliver_next()? {}
+        Ok(())
+    }
+
+    // Loses the oldest queued message regardless of partitions
+    pub fn drop_next(&mut self) -> Option<Message<Payload>> {
+        self.in_flight.pop_front()
+    }
+
+    // Delivers a message taken off the queue with drop_next, e.g. to hand it over after ones queued behind it
+    pub fn deliver(&mut self, message: Message<Payload>) -> anyhow::Result<()> {
+        let dest = message.dest.clone();
+        self.step(dest.as_str(), Event::Message(message))
+    }
+
+    // Splits the cluster so nodes only reach others in their own group; nodes left out are cut off on their own
+    pub fn partition(&mut self, groups: &[&[&str]]) {
+        self.sides = self
+            .node_ids
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let side = groups
+                    .iter()
+                    .position(|group| group.contains(&node.as_str()))
+                    .unwrap_or(groups.len() + i);
+                (node.clone(), side)
+            })
+            .collect();
+    }
+
+    pub fn heal(&mut self) {
+        self.sides.clear();
+    }
+
+    // Every reply sent to a client since the last call, in the order they were sent
+    pub fn take_client_replies(&mut self) -> Vec<Message<Payload>> {
+        std::mem::take(&mut self.client_replies)
+    }
+
+    fn connected(&self, a: &str, b: &str) -> bool {
+        match (self.sides.get(a), self.sides.get(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        }
+    }
+
+    fn step(&mut self, node: &str, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
+        let Some(state) = self.nodes.get_mut(node) else {
+            anyhow::bail!("No node {} in the cluster", node);
+        };
+        for message in test_harness::drive(state, vec![event])? {
+            if self.nodes.contains_key(&message.dest) {
+                self.in_flight.push_back(message);
+            } else {
+                self.client_replies.push(message);
+            }
+        }
+        Ok(())
+    }
+}
+
+// How long a child process gets to answer before the test gives up on it
+const PROCESS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/*
+A node run as a child `rustengan <mode>` process, fed over its stdin and stdout like Maelstrom would. `program` is the
+rustengan binary to run; integration tests get it from env!("CARGO_BIN_EXE_rustengan"). This is for
+nodes that block on rpc (e.g. anything using KvClient), which Cluster can't drive; whatever the child sends to the
+lin-kv or seq-kv service is answered by one in-process LinKvNode, so the store can be preloaded (or overwritten
+behind the child's back) directly.
+The child is a single node, n0, and is killed when this is dropped.
+*/
+pub struct Process {
+    child: Child,
+    // None once close_input has sent EOF
+    stdin: Option<ChildStdin>,
+    lines: mpsc::Receiver<String>,
+    lin_kv: LinKvNode,
+    // Sees each kv request before the store does, and can have a write land first (see before_kv_request)
+    conflicting_writer: Option<ConflictingWriter>,
+    // Picks kv requests to fail instead of applying (see fail_kv_requests)
+    failing: Option<FailingRequests>,
+    next_client_msg_id: usize,
+}
+
+type ConflictingWriter =
+    Box<dyn FnMut(&KvPayload) -> Option<(serde_json::Value, serde_json::Value)>>;
+type FailingRequests = Box<dyn FnMut(&KvPayload) -> Option<u64>>;
+
+impl Process {
+    // Starts the child and waits for it to answer Init
+    pub fn spawn(program: impl AsRef<OsStr>, mode: &str) -> anyhow::Result<Self> {
+        Process::spawn_with_env(program, mode, &[])
+    }
+
+    // Like spawn, with extra environment variables set for the child
+    pub fn spawn_with_env(
+        program: impl AsRef<OsStr>,
+        mode: &str,
+        vars: &[(&str, &str)],
+    ) -> anyhow::Result<Self> {
+        Process::spawn_with_state(program, mode, vars, HashMap::new())
+    }
+
+    // Like spawn_with_env, with the kv store already holding `state` when the child starts
+    pub fn spawn_with_state(
+        program: impl AsRef<OsStr>,
+        mode: &str,
+        vars: &[(&str, &str)],
+        state: HashMap<lin_kv::Value, lin_kv::Value>,
+    ) -> anyhow::Result<Self> {
+        let lin_kv = test_harness::init::<LinKvNode, _, _>(KV_STAND_IN, &[KV_STAND_IN])?;
+        let mut child = Command::new(program)
+            .arg(mode)
+            .envs(vars.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Couldn't start a {} node", mode))?;
+        let stdin = child.stdin.take().context("Child has no stdin")?;
+        let stdout = child.stdout.take().context("Child has no stdout")?;
+        // Read on a thread so a child that never answers fails the test instead of hanging it
+        let (tx, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut process = Process {
+            child,
+            stdin: Some(stdin),
+            lines,
+            lin_kv: lin_kv.with_state(state),
+            conflicting_writer: None,
+            failing: None,
+            next_client_msg_id: 0,
+        };
+        let init = InitPayload::Init(Init {
+            node_id: NodeId::from("n0"),
+            node_ids: vec![NodeId::from("n0")],
+        });
+        let _: InitPayload = process.request(init)?;
+        Ok(process)
+    }
+
+    // Stores `value` under `key` in the kv store, as if some node had written it
+    pub fn preload(&mut self, key: impl Serialize, value: impl Serialize) -> anyhow::Result<()> {
+        let write = KvPayload::Write {
+            key: serde_json::to_value(key)?,
+            value: serde_json::to_value(value)?,
+        };
+        let message = Message {
+            src: NodeId::from(CLIENT),
+            dest: NodeId::from(KV_STAND_IN),
+            body: MessageBody {
+                msg_id: Some(MsgId(0)),
+                in_reply_to: None,
+                payload: write,
+            },
+        };
+        match test_harness::drive(&mut self.lin_kv, vec![Event::Message(message)])?.pop() {
+            Some(reply) if matches!(reply.body.payload, KvPayload::WriteOk {}) => Ok(()),
+            reply => bail!("Preloading lin-kv failed: {:?}", reply),
+        }
+    }
+
+    /*
+    Stands in for another node writing to the kv store at the worst moment: `writer` is shown every kv request the
+    child makes, and a (key, value) it returns is written just before that request is applied. Returning a write for
+    a cas makes the child lose that CAS race.
+    */
+    pub fn before_kv_request(
+        &mut self,
+        writer: impl FnMut(&KvPayload) -> Option<(serde_json::Value, serde_json::Value)> + 'static,
+    ) {
+        self.conflicting_writer = Some(Box::new(writer));
+    }
+
+    // Stands in for the kv store failing: every kv request the child makes that `fails` returns an error code for is
+    // answered with that error (e.g. error_code::TIMEOUT) without being applied
+    pub fn fail_kv_requests(&mut self, fails: impl FnMut(&KvPayload) -> Option<u64> + 'static) {
+        self.failing = Some(Box::new(fails));
+    }
+
+    // Sends a client request and returns the child's reply, answering its lin-kv requests in the meantime
+    pub fn request<Request: Serialize, Response: DeserializeOwned>(
+        &mut self,
+        payload: Request,
+    ) -> anyhow::Result<Response> {
+        let msg_id = self.send(payload)?;
+        self.reply_to(msg_id)
+    }
+
+    // Sends a client request again under the `msg_id` it was first sent with, as a client retrying after a lost
+    // reply does, and returns the child's reply
+    pub fn retry<Request: Serialize, Response: DeserializeOwned>(
+        &mut self,
+        msg_id: MsgId,
+        payload: Request,
+    ) -> anyhow::Result<Response> {
+        self.write(&self.client_message(msg_id, payload))?;
+        self.reply_to(msg_id)
+    }
+
+    // Waits for the child's reply to a request sent as `msg_id`, answering its lin-kv requests in the meantime
+    pub fn reply_to<Response: DeserializeOwned>(
+        &mut self,
+        msg_id: MsgId,
+    ) -> anyhow::Result<Response> {
+        loop {
+            let line = self
+                .lines
+                .recv_timeout(PROCESS_TIMEOUT)
+                .context("Child didn't answer in time")?;
+            let message: Message<serde_json::Value> = serde_json::from_str(&line)
+                .with_context(|| format!("Child wrote a malformed message {}", line))?;
+            if KV_SERVICES.contains(&message.dest.as_str()) {
+                let service = message.dest;
+                let mut request: Message<KvPayload> = serde_json::from_str(&line)?;
+                if let Some(fails) = self.failing.as_mut() {
+                    if let Some(code) = fails(&request.body.payload) {
+                        let reply = Message {
+                            src: service,
+                            dest: request.src,
+                            body: MessageBody {
+                                msg_id: None,
+                                in_reply_to: request.body.msg_id,
+                                payload: KvPayload::Error {
+                                    code,
+                                    text: "injected failure".to_string(),
+                                },
+                            },
+                        };
+                        self.write(&reply)?;
+                        continue;
+                    }
+                }
+                request.dest = NodeId::from(KV_STAND_IN);
+                let conflict = self
+                    .conflicting_writer
+                    .as_mut()
+                    .and_then(|writer| writer(&request.body.payload));
+                if let Some((key, value)) = conflict {
+                    self.preload(key, value)?;
+                }
+                for mut reply in
+                    test_harness::drive(&mut self.lin_kv, vec![Event::Message(request)])?
+                {
+                    reply.src = service.clone();
+                    self.write(&reply)?;
+                }
+            } else if message.body.in_reply_to == Some(msg_id) {
+                return serde_json::from_value(message.body.payload)
+                    .with_context(|| format!("Unexpected reply {}", line));
+            }
+        }
+    }
+
+    // Sends a client request without waiting for the reply
+    pub fn send(&mut self, payload: impl Serialize) -> anyhow::Result<MsgId> {
+        let msg_id = MsgId(self.next_client_msg_id);
+        self.next_client_msg_id += 1;
+        self.write(&self.client_message(msg_id, payload))?;
+        Ok(msg_id)
+    }
+
+    fn client_message<P>(&self, msg_id: MsgId, payload: P) -> Message<P> {
+        Message {
+            src: NodeId::from(CLIENT),
+            dest: NodeId::from("n0"),
+            body: MessageBody {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        }
+    }
+
+    // Every line the child has written so far that nothing has read yet
+    pub fn written(&mut self) -> Vec<String> {
+        self.lines.try_iter().collect()
+    }
+
+    // Sends SIGTERM, as Maelstrom does, and waits for the child to exit; returns its status and every line it wrote
+    // that hadn't been read yet
+    pub fn terminate(&mut self) -> anyhow::Result<(ExitStatus, Vec<String>)> {
+        shutdown::terminate(self.child.id())?;
+        self.wait_for_exit("SIGTERM")
+    }
+
+    // Closes the child's stdin, as a driver that runs out of input does, and waits for it to exit; returns the same
+    // as terminate
+    pub fn close_input(&mut self) -> anyhow::Result<(ExitStatus, Vec<String>)> {
+        self.stdin = None;
+        self.wait_for_exit("EOF")
+    }
+
+    fn wait_for_exit(&mut self, cause: &str) -> anyhow::Result<(ExitStatus, Vec<String>)> {
+        let mut lines = Vec::new();
+        loop {
+            match self.lines.recv_timeout(PROCESS_TIMEOUT) {
+                Ok(line) => lines.push(line),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => bail!("Child didn't exit after {}", cause),
+            }
+        }
+        Ok((self.child.wait()?, lines))
+    }
+
+    fn write(&mut self, message: &impl Serialize) -> anyhow::Result<()> {
+        let stdin = self.stdin.as_mut().context("Child's input is closed")?;
+        serde_json::to_writer(&mut *stdin, message)?;
+        stdin.write_all(b"\n")?;
+        Ok(stdin.flush()?)
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,45 @@
+use crate::{step_node, Event, Init, Message, Network, Node, NodeId};
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde_json::Deserializer;
+use std::sync::mpsc;
+
+/*
+Deterministic, in-memory way to drive a node through a script of events without going through stdin/stdout.
+Nodes that block on rpc() (e.g. anything using KvClient) can't be driven this way, since nothing will ever reply.
+Anything the node injects into its own event loop is discarded; script Event::Injected inputs instead.
+*/
+
+// Builds a node the same way run_node does once Init arrives
+pub fn init<N, Payload, InjectedPayload>(node_id: &str, node_ids: &[&str]) -> anyhow::Result<N>
+where
+    N: Node<Payload, InjectedPayload>,
+{
+    let init = Init {
+        node_id: NodeId::from(node_id),
+        node_ids: node_ids.iter().map(|id| NodeId::from(*id)).collect(),
+    };
+    let (inject, _) = mpsc::channel();
+    N::from_init(Network::new(init)?, inject)
+}
+
+// Runs every input through step in order and returns every message the node wrote, in order. As in run_node, a
+// failed request is answered with an error reply, and only errors other than MaelstromError stop the run.
+pub fn drive<N, Payload, InjectedPayload>(
+    node: &mut N,
+    inputs: Vec<Event<Payload, InjectedPayload>>,
+) -> anyhow::Result<Vec<Message<Payload>>>
+where
+    N: Node<Payload, InjectedPayload>,
+    Payload: DeserializeOwned,
+{
+    let mut output = Vec::new();
+    for input in inputs {
+        step_node(node, input, &mut output).context("Node step function failed")?;
+    }
+    Deserializer::from_slice(&output)
+        .into_iter::<Message<Payload>>()
+        .map(|reply| reply.context("Node wrote a message that could not be deserialized!"))
+        .collect()
+}
@@ -0,0 +1,75 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/*
+A single micro-operation inside a Maelstrom txn. On the wire these are mixed-type JSON arrays:
+["r", key, null] for a read (the reply fills in the value read) and ["w", key, value] for a write.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnOp {
+    Read { key: i64, value: Option<i64> },
+    Write { key: i64, value: i64 },
+}
+
+impl Serialize for TxnOp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            TxnOp::Read { key, value } => ("r", key, value).serialize(serializer),
+            TxnOp::Write { key, value } => ("w", key, Some(value)).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TxnOp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (op, key, value) = <(String, i64, Option<i64>)>::deserialize(deserializer)?;
+        match (op.as_str(), value) {
+            ("r", value) => Ok(TxnOp::Read { key, value }),
+            ("w", Some(value)) => Ok(TxnOp::Write { key, value }),
+            ("w", None) => Err(de::Error::custom("txn write is missing its value")),
+            (op, _) => Err(de::Error::unknown_variant(op, &["r", "w"])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Both op shapes have to parse from Maelstrom's arrays and write back as exactly the same arrays
+    #[test]
+    fn ops_round_trip_as_arrays() -> anyhow::Result<()> {
+        let cases = [
+            (
+                r#"["r",1,null]"#,
+                TxnOp::Read {
+                    key: 1,
+                    value: None,
+                },
+            ),
+            (r#"["w",1,42]"#, TxnOp::Write { key: 1, value: 42 }),
+        ];
+        for (json, op) in cases {
+            assert_eq!(serde_json::from_str::<TxnOp>(json)?, op);
+            assert_eq!(serde_json::to_string(&op)?, json);
+        }
+        // A read the node has answered carries the value it saw
+        let answered = TxnOp::Read {
+            key: 1,
+            value: Some(5),
+        };
+        assert_eq!(serde_json::to_string(&answered)?, r#"["r",1,5]"#);
+        Ok(())
+    }
+
+    // A write without a value and an op other than r or w have to be rejected, not guessed at
+    #[test]
+    fn malformed_ops_are_rejected() {
+        for json in [r#"["w",1,null]"#, r#"["x",1,2]"#, r#"["r","1",null]"#] {
+            assert!(
+                serde_json::from_str::<TxnOp>(json).is_err(),
+                "{} parsed",
+                json
+            );
+        }
+    }
+}
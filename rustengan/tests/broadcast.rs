@@ -0,0 +1,109 @@
+use rustengan::nodes::broadcast::BroadcastPayload;
+use rustengan::simulation::Process;
+use rustengan::{Message, MsgId};
+
+use anyhow::{bail, Context};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+
+const RUSTENGAN: &str = env!("CARGO_BIN_EXE_rustengan");
+
+/*
+A broadcast node run as a real process, so its stdin reader, gossip ticker and event loop all run at once, is sent a
+stream of gossip batches with reads and single broadcasts mixed in, without waiting for any answer. Every read has to
+hold every value of every batch and broadcast acked before it was sent, none partly applied.
+*/
+#[test]
+fn reads_see_every_batch_acked_before_them() -> anyhow::Result<()> {
+    const BATCHES: i64 = 300;
+    const BATCH_SIZE: i64 = 20;
+    let mut node = Process::spawn_with_env(RUSTENGAN, "broadcast", &[("GOSSIP_INTERVAL_MS", "1")])?;
+    // The values each request added, by msg_id
+    let mut sent: Vec<(MsgId, Vec<i64>)> = Vec::new();
+    let mut reads = HashSet::new();
+    for batch in 0..BATCHES {
+        let messages: Vec<i64> = (batch * BATCH_SIZE..(batch + 1) * BATCH_SIZE).collect();
+        let msg_id = node.send(BroadcastPayload::BroadcastBatch {
+            messages: messages.clone(),
+        })?;
+        sent.push((msg_id, messages));
+        let message = -batch - 1;
+        sent.push((
+            node.send(BroadcastPayload::Broadcast { message })?,
+            vec![message],
+        ));
+        reads.insert(node.send(BroadcastPayload::<i64>::Read { from: None })?);
+    }
+
+    let (status, lines) = node.terminate()?;
+    assert!(status.success(), "node exited with {}", status);
+    let mut acked = HashSet::new();
+    let mut read_values = HashMap::new();
+    for line in &lines {
+        let reply: Message<BroadcastPayload> = serde_json::from_str(line)
+            .with_context(|| format!("wrote a broken line {:?}", line))?;
+        let Some(in_reply_to) = reply.body.in_reply_to else {
+            continue;
+        };
+        match reply.body.payload {
+            BroadcastPayload::BroadcastBatchOk {} | BroadcastPayload::BroadcastOk {} => {
+                acked.insert(in_reply_to);
+            }
+            BroadcastPayload::ReadOk { messages, .. } if reads.contains(&in_reply_to) => {
+                read_values.insert(in_reply_to, messages.into_iter().collect::<HashSet<_>>());
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(
+        read_values.len(),
+        reads.len(),
+        "not every read was answered"
+    );
+    for (read, values) in &read_values {
+        for (msg_id, messages) in &sent {
+            if msg_id.0 < read.0 && acked.contains(msg_id) {
+                let missing: Vec<_> = messages.iter().filter(|m| !values.contains(m)).collect();
+                assert!(
+                    missing.is_empty(),
+                    "read {} is missing {:?} from {}",
+                    read,
+                    missing,
+                    msg_id
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read(node: &mut Process) -> anyhow::Result<Vec<i64>> {
+    match node.request(BroadcastPayload::<i64>::Read { from: None })? {
+        BroadcastPayload::ReadOk { messages, .. } => Ok(messages),
+        other => bail!("read got {:?}", other),
+    }
+}
+
+/*
+A second copy of the node's Init has to be acked again without wiping what the node already holds, and an Init that
+tries to give it another identity has to be refused with code 10, leaving the node as it was.
+*/
+#[test]
+fn repeated_init_keeps_the_broadcast_set() -> anyhow::Result<()> {
+    let mut node = Process::spawn(RUSTENGAN, "broadcast")?;
+    let _: BroadcastPayload = node.request(BroadcastPayload::Broadcast { message: 7 })?;
+
+    let same = json!({"type": "init", "node_id": "n0", "node_ids": ["n0"]});
+    let reply: serde_json::Value = node.request(same)?;
+    assert_eq!(reply["type"], "init_ok");
+    assert_eq!(read(&mut node)?, [7]);
+
+    let other = json!({"type": "init", "node_id": "n1", "node_ids": ["n0", "n1"]});
+    let reply: serde_json::Value = node.request(other)?;
+    assert_eq!(
+        (&reply["type"], &reply["code"]),
+        (&json!("error"), &json!(10))
+    );
+    assert_eq!(read(&mut node)?, [7]);
+    Ok(())
+}
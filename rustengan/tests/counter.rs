@@ -0,0 +1,87 @@
+use rustengan::error_code;
+use rustengan::kv::KvPayload;
+use rustengan::nodes::counter::{CounterPayload, COUNTER_KEY};
+use rustengan::simulation::Process;
+
+use anyhow::bail;
+use std::cell::Cell;
+use std::rc::Rc;
+
+const RUSTENGAN: &str = env!("CARGO_BIN_EXE_rustengan");
+
+fn kv_counter() -> anyhow::Result<Process> {
+    Process::spawn_with_env(RUSTENGAN, "counter", &[("COUNTER_MODE", "kv")])
+}
+
+fn add(node: &mut Process, delta: i64) -> anyhow::Result<()> {
+    match node.request(CounterPayload::Add { delta })? {
+        CounterPayload::AddOk {} => Ok(()),
+        other => bail!("add got {:?}", other),
+    }
+}
+
+fn read(node: &mut Process) -> anyhow::Result<i64> {
+    match node.request(CounterPayload::Read {})? {
+        CounterPayload::ReadOk { value } => Ok(value),
+        other => bail!("read got {:?}", other),
+    }
+}
+
+/*
+A kv-mode counter node adds 5, then the store is wound back to 2 behind its back, which is what a stale seq-kv read
+looks like to the node. Reading must still give 5, and once the store moves past that, the higher value.
+*/
+#[test]
+fn stale_read_never_goes_below_own_writes() -> anyhow::Result<()> {
+    let mut node = kv_counter()?;
+    add(&mut node, 5)?;
+    node.preload(COUNTER_KEY, 2)?;
+    assert_eq!(read(&mut node)?, 5);
+    node.preload(COUNTER_KEY, 8)?;
+    assert_eq!(read(&mut node)?, 8);
+    Ok(())
+}
+
+// Another writer changes the counter just before each of the add's first few CASes, so each of those loses and
+// cas_update has to back off and retry from a fresh read
+#[test]
+fn contended_add_lands_on_top_of_the_last_write() -> anyhow::Result<()> {
+    const CONFLICTS: i64 = 3;
+    let mut node = kv_counter()?;
+    let cas_attempts = Rc::new(Cell::new(0));
+    let attempts = Rc::clone(&cas_attempts);
+    node.before_kv_request(move |request| {
+        if !matches!(request, KvPayload::Cas { .. }) {
+            return None;
+        }
+        attempts.set(attempts.get() + 1);
+        (attempts.get() <= CONFLICTS).then(|| (COUNTER_KEY.into(), (100 + attempts.get()).into()))
+    });
+
+    add(&mut node, 5)?;
+    assert_eq!(read(&mut node)?, 100 + CONFLICTS + 5);
+    assert_eq!(cas_attempts.get(), CONFLICTS + 1);
+    Ok(())
+}
+
+/*
+The store times out the node's first read of the counter. The client's read has to get the timeout (code 0) back, as
+it would from seq-kv itself, and the node has to carry on serving adds and reads afterwards.
+*/
+#[test]
+fn kv_timeout_is_answered_and_survived() -> anyhow::Result<()> {
+    let mut node = kv_counter()?;
+    let mut timed_out = false;
+    node.fail_kv_requests(move |request| {
+        let fail = !timed_out && matches!(request, KvPayload::Read { .. });
+        timed_out |= fail;
+        fail.then_some(error_code::TIMEOUT)
+    });
+
+    let reply: serde_json::Value = node.request(CounterPayload::Read {})?;
+    assert_eq!(reply["type"], "error", "the read got {}", reply);
+    assert_eq!(reply["code"], error_code::TIMEOUT);
+    add(&mut node, 3)?;
+    assert_eq!(read(&mut node)?, 3);
+    Ok(())
+}
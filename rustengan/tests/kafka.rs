@@ -0,0 +1,166 @@
+use rustengan::error_code;
+use rustengan::kv::KvPayload;
+use rustengan::nodes::kafka::{KafkaNode, KafkaPayload};
+use rustengan::simulation::Process;
+
+use anyhow::bail;
+use std::collections::HashMap;
+
+const RUSTENGAN: &str = env!("CARGO_BIN_EXE_rustengan");
+const MAX_POLL_ENTRIES: usize = 100;
+
+// A kafka node whose lin-kv store already holds `logs`
+fn kafka(logs: &HashMap<String, Vec<i64>>) -> anyhow::Result<Process> {
+    let max = MAX_POLL_ENTRIES.to_string();
+    Process::spawn_with_state(
+        RUSTENGAN,
+        "kafka",
+        &[("KAFKA_MAX_POLL_ENTRIES", &max)],
+        KafkaNode::kv_state_with_logs(logs),
+    )
+}
+
+fn poll(node: &mut Process, key: &str, offset: usize) -> anyhow::Result<Vec<(usize, i64)>> {
+    let poll = KafkaPayload::Poll {
+        offsets: HashMap::from([(key.to_string(), offset)]),
+    };
+    match node.request(poll)? {
+        KafkaPayload::PollOk { mut msgs } => Ok(msgs.remove(key).unwrap_or_default()),
+        other => bail!("poll got {:?}", other),
+    }
+}
+
+fn list_committed(node: &mut Process, keys: &[&str]) -> anyhow::Result<HashMap<String, usize>> {
+    let list = KafkaPayload::ListCommittedOffsets {
+        keys: keys.iter().map(|key| key.to_string()).collect(),
+    };
+    match node.request(list)? {
+        KafkaPayload::ListCommittedOffsetsOk { offsets } => Ok(offsets),
+        other => bail!("list got {:?}", other),
+    }
+}
+
+/*
+Polls at, past and before the end of a preloaded log, and for a key nothing was sent to, have to come back with
+exactly the entries from their offset to the end, without the node crashing.
+*/
+#[test]
+fn polls_return_the_rest_of_the_log() -> anyhow::Result<()> {
+    let logs = HashMap::from([("k1".to_string(), vec![10, 11, 12])]);
+    let mut node = kafka(&logs)?;
+    let cases = [
+        ("k1", 1, vec![(1, 11), (2, 12)]),
+        ("k1", 3, vec![]),
+        ("k1", 8, vec![]),
+        ("k1", usize::MAX, vec![]),
+        ("unknown", 0, vec![]),
+    ];
+    for (key, offset, expected) in cases {
+        assert_eq!(
+            poll(&mut node, key, offset)?,
+            expected,
+            "polling {} at {}",
+            key,
+            offset
+        );
+    }
+    Ok(())
+}
+
+// A 1000-entry log has to come back in pages of at most max_poll_entries, each continuing where the last one ended
+#[test]
+fn long_logs_are_paged() -> anyhow::Result<()> {
+    let log: Vec<i64> = (0..1000).map(|i| i * 7).collect();
+    let mut node = kafka(&HashMap::from([("long".to_string(), log.clone())]))?;
+    let mut offset = 0;
+    while offset < log.len() {
+        let page = poll(&mut node, "long", offset)?;
+        let expected: Vec<(usize, i64)> = (offset..log.len().min(offset + MAX_POLL_ENTRIES))
+            .map(|offset| (offset, log[offset]))
+            .collect();
+        assert_eq!(page, expected, "polling at {}", offset);
+        offset += page.len();
+    }
+    Ok(())
+}
+
+/*
+Commits of 5 and 3 to a key, in either order, have to leave 5 committed, and listing those keys along with
+never-committed ones has to leave the never-committed ones out (clients read absent as uncommitted, not 0).
+*/
+#[test]
+fn commits_only_move_forward() -> anyhow::Result<()> {
+    let logs = HashMap::from([("k1".to_string(), vec![10, 11, 12])]);
+    let mut node = kafka(&logs)?;
+    for (key, order) in [("up", [3, 5]), ("down", [5, 3])] {
+        for offset in order {
+            let commit = KafkaPayload::CommitOffsets {
+                offsets: HashMap::from([(key.to_string(), offset)]),
+            };
+            match node.request(commit)? {
+                KafkaPayload::CommitOffsetsOk {} => {}
+                other => bail!("commit got {:?}", other),
+            }
+        }
+        assert_eq!(list_committed(&mut node, &[key])?.get(key), Some(&5));
+    }
+
+    // "k1" has a log but was never committed, so it has to be left out along with a key nothing was sent to
+    let expected = HashMap::from([("up".to_string(), 5), ("down".to_string(), 5)]);
+    assert_eq!(
+        list_committed(&mut node, &["up", "k1", "down", "never"])?,
+        expected
+    );
+    Ok(())
+}
+
+/*
+The store fails the write of a send's entry. That send gets an error, and the next send to the key has to be readable
+from offset 0 rather than sit behind a hole where the failed entry would have gone.
+*/
+#[test]
+fn failed_entry_write_leaves_no_hole() -> anyhow::Result<()> {
+    let mut node = kafka(&HashMap::new())?;
+    let mut failed = false;
+    node.fail_kv_requests(move |request| {
+        let key = match request {
+            KvPayload::Write { key, .. } | KvPayload::Cas { key, .. } => key,
+            _ => return None,
+        };
+        let fail = !failed && key == "log/k1/0";
+        failed |= fail;
+        fail.then_some(error_code::CRASH)
+    });
+
+    let send = |msg| KafkaPayload::Send {
+        key: "k1".to_string(),
+        msg,
+    };
+    let reply: serde_json::Value = node.request(send(1))?;
+    assert_eq!(reply["type"], "error", "the failed send got {}", reply);
+    let offset = match node.request(send(2))? {
+        KafkaPayload::SendOk { offset } => offset,
+        other => bail!("send got {:?}", other),
+    };
+    assert_eq!(poll(&mut node, "k1", 0)?, vec![(offset, 2)]);
+    Ok(())
+}
+
+// A client retrying a send whose reply it lost has to get the offset the first attempt appended at, without the
+// message being appended a second time
+#[test]
+fn retried_send_appends_once() -> anyhow::Result<()> {
+    let mut node = kafka(&HashMap::new())?;
+    let send = || KafkaPayload::Send {
+        key: "k1".to_string(),
+        msg: 7,
+    };
+    let msg_id = node.send(send())?;
+    let offsets = [node.reply_to(msg_id)?, node.retry(msg_id, send())?].map(|reply| match reply {
+        KafkaPayload::SendOk { offset } => Some(offset),
+        _ => None,
+    });
+    assert_eq!(offsets, [Some(0), Some(0)]);
+    assert_eq!(poll(&mut node, "k1", 0)?, vec![(0, 7)]);
+    Ok(())
+}
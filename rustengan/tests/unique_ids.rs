@@ -0,0 +1,69 @@
+use rustengan::nodes::unique_id::UniqueIDPayload;
+use rustengan::simulation::Process;
+use rustengan::Message;
+
+use anyhow::Context;
+use std::collections::HashSet;
+use std::time::Duration;
+
+const RUSTENGAN: &str = env!("CARGO_BIN_EXE_rustengan");
+
+/*
+A unique-ids node gets a burst of generates while a long coalescing window holds every reply in its stdout writer,
+then is sent SIGTERM. It has to exit cleanly having written every reply, each as a whole line of JSON.
+*/
+#[test]
+fn sigterm_flushes_held_back_replies() -> anyhow::Result<()> {
+    const REQUESTS: usize = 200;
+    let mut node = Process::spawn_with_env(
+        RUSTENGAN,
+        "unique-ids",
+        &[("STDOUT_COALESCE_WINDOW_MS", "60000")],
+    )?;
+    for _ in 0..REQUESTS {
+        node.send(UniqueIDPayload::Generate {})?;
+    }
+    // Long enough for the node to read and answer the burst; the replies are held back by the window
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(
+        node.written(),
+        Vec::<String>::new(),
+        "replies were written before SIGTERM"
+    );
+
+    let (status, lines) = node.terminate()?;
+    assert!(status.success(), "node exited with {} on SIGTERM", status);
+    let mut answered = HashSet::new();
+    for line in &lines {
+        let reply: Message<UniqueIDPayload> = serde_json::from_str(line)
+            .with_context(|| format!("wrote a broken line {:?}", line))?;
+        answered.extend(reply.body.in_reply_to);
+    }
+    assert_eq!(answered.len(), REQUESTS);
+    Ok(())
+}
+
+// The same burst, but the node's input is closed instead: replies still held back when it sees EOF must be written
+#[test]
+fn eof_flushes_held_back_replies() -> anyhow::Result<()> {
+    const REQUESTS: usize = 200;
+    let mut node = Process::spawn_with_env(
+        RUSTENGAN,
+        "unique-ids",
+        &[("STDOUT_COALESCE_WINDOW_MS", "60000")],
+    )?;
+    for _ in 0..REQUESTS {
+        node.send(UniqueIDPayload::Generate {})?;
+    }
+
+    let (status, lines) = node.close_input()?;
+    assert!(status.success(), "node exited with {} on EOF", status);
+    let mut answered = HashSet::new();
+    for line in &lines {
+        let reply: Message<UniqueIDPayload> = serde_json::from_str(line)
+            .with_context(|| format!("wrote a broken line {:?}", line))?;
+        answered.extend(reply.body.in_reply_to);
+    }
+    assert_eq!(answered.len(), REQUESTS);
+    Ok(())
+}